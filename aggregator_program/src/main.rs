@@ -2,57 +2,117 @@
 
 #![no_main]
 sp1_zkvm::entrypoint!(main);
-use rs_merkle::{algorithms::Sha256 as MerkleSha256, Hasher, MerkleTree};
+use alloy_sol_types::SolType;
 use sha2::{Digest, Sha256};
+use shared_merkle::{
+    accumulator::Accumulator,
+    digest_pair_leaves, first_duplicate, proof_pair_leaves,
+    public_values::{chain_digest, AggregationPublicValues},
+    tree_root, words_to_bytes_le, Scheme, Submitter,
+};
 
-pub fn words_to_bytes_le(words: &[u32; 8]) -> [u8; 32] {
-    let mut bytes = [0u8; 32];
-    for i in 0..8 {
-        let word_bytes = words[i].to_le_bytes();
-        bytes[i * 4..(i + 1) * 4].copy_from_slice(&word_bytes);
-    }
-    bytes
-}
+pub fn main() {
+    // Read the epoch and the root this aggregate replaces.
+    let epoch = sp1_zkvm::io::read::<u64>();
+    let prev_root = sp1_zkvm::io::read::<[u8; 32]>();
 
-/// Encode a list of vkeys and committed values into a single byte array using a merkle tree
-pub fn commit_proof_pairs(vkeys: &[[u32; 8]], committed_values: &[Vec<u8>]) -> Vec<u8> {
-    assert_eq!(vkeys.len(), committed_values.len());
+    // Read how leaves and nodes are hashed.
+    let scheme = sp1_zkvm::io::read::<Scheme>();
 
-    // Prepare the leaves by concatenating vkey and value, then hashing
-    let leaves: Vec<[u8; 32]> = vkeys
-        .iter()
-        .zip(committed_values.iter())
-        .map(|(vkey, value)| {
-            let concat = [&words_to_bytes_le(vkey)[..], value].concat();
-            MerkleSha256::hash(&concat)
-        })
-        .collect();
+    // Read the peaks of the accumulator over every earlier epoch.
+    let mut accumulator = sp1_zkvm::io::read::<Accumulator>();
+    assert!(accumulator.is_well_formed());
 
-    // Create the Merkle tree and get the root
-    let merkle_tree = MerkleTree::<MerkleSha256>::from_leaves(&leaves);
-    merkle_tree
-        .root()
-        .expect("Tree should have a root with valid leaves")
-        .to_vec()
-}
+    // Read our own vkey and the public values of the previous aggregate, if we extend its chain.
+    let self_vkey = sp1_zkvm::io::read::<[u32; 8]>();
+    let prev_aggregate = sp1_zkvm::io::read::<Option<Vec<u8>>>();
 
-pub fn main() {
     // Read the verification keys.
     let vkeys = sp1_zkvm::io::read::<Vec<[u32; 8]>>();
 
-    // Read the public values.
+    // Read the public values, or only their sha256 when leaves commit the digest.
     let public_values = sp1_zkvm::io::read::<Vec<Vec<u8>>>();
 
+    // Read who submitted each proof, empty unless the leaf version binds it.
+    let submitters = sp1_zkvm::io::read::<Vec<Submitter>>();
+
+    // Read the salts of hashed public values, empty when nothing is salted.
+    let salts = sp1_zkvm::io::read::<Vec<Option<[u8; 32]>>>();
+
     // Verify the proofs.
     assert_eq!(vkeys.len(), public_values.len());
-    for i in 0..vkeys.len() {
-        let vkey = &vkeys[i];
-        let public_values = &public_values[i];
-        let public_values_digest = Sha256::digest(public_values);
-        sp1_zkvm::lib::verify::verify_sp1_proof(vkey, &public_values_digest.into());
+    let digests: Vec<[u8; 32]> = public_values
+        .iter()
+        .map(|values| {
+            if scheme.hashed_public_values {
+                values
+                    .as_slice()
+                    .try_into()
+                    .expect("Hashed public values should be sha256 digests")
+            } else {
+                Sha256::digest(values).into()
+            }
+        })
+        .collect();
+    for (vkey, digest) in vkeys.iter().zip(digests.iter()) {
+        sp1_zkvm::lib::verify::verify_sp1_proof(vkey, digest);
     }
 
-    // Only commit the root
-    let root = commit_proof_pairs(&vkeys, &public_values);
-    sp1_zkvm::io::commit_slice(&root);
+    // Commit the root along with the epoch it belongs to. Hashed leaves reuse the digests
+    // the proofs were verified with.
+    let leaves = if scheme.hashed_public_values {
+        digest_pair_leaves(scheme, &vkeys, &digests, &submitters, &salts)
+    } else {
+        proof_pair_leaves(scheme, &vkeys, &public_values, &submitters, &salts)
+    }
+    .expect("Every proof should have a vkey and, if bound, a submitter");
+    if let Some((earlier, repeat)) = first_duplicate(&leaves) {
+        panic!("Leaf {} repeats leaf {}", repeat, earlier);
+    }
+    let root =
+        tree_root(scheme, &leaves).expect("Tree should have leaves, or a padded depth they fit in");
+
+    // Append this epoch's leaves to the accumulator
+    let prev_accumulator_root = accumulator.root(scheme);
+    accumulator.extend(scheme, &leaves);
+
+    // Verify the previous aggregate with our own vkey and pick up its chain
+    let self_vkey_bytes = words_to_bytes_le(&self_vkey);
+    let (prev_chain_digest, chain_start_epoch) = match prev_aggregate {
+        Some(prev_public_values) => {
+            let prev_digest = Sha256::digest(&prev_public_values);
+            sp1_zkvm::lib::verify::verify_sp1_proof(&self_vkey, &prev_digest.into());
+            let prev = AggregationPublicValues::abi_decode(&prev_public_values, true)
+                .expect("Previous aggregate should commit aggregation public values");
+            assert_eq!(prev.selfVkey, self_vkey_bytes);
+            assert!(prev.epoch < epoch);
+            assert_eq!(prev.leafVersion, scheme.leaf_version as u8);
+            assert_eq!(prev.treeHash, scheme.tree_hash as u8);
+            assert_eq!(prev.hashedPublicValues, scheme.hashed_public_values);
+            assert_eq!(prev.treeDepth, scheme.committed_depth());
+            assert_eq!(prev.newRoot, prev_root);
+            assert_eq!(prev.accumulatorRoot, prev_accumulator_root);
+            (prev.chainDigest.into(), prev.chainStartEpoch)
+        }
+        None => ([0u8; 32], epoch),
+    };
+    let accumulator_root = accumulator.root(scheme);
+
+    let output = AggregationPublicValues {
+        epoch,
+        leafCount: vkeys.len() as u64,
+        prevRoot: prev_root.into(),
+        newRoot: root.into(),
+        prevAccumulatorRoot: prev_accumulator_root.into(),
+        accumulatorRoot: accumulator_root.into(),
+        accumulatorLeafCount: accumulator.leaf_count,
+        selfVkey: self_vkey_bytes.into(),
+        chainDigest: chain_digest(&prev_chain_digest, epoch, &root, &accumulator_root).into(),
+        chainStartEpoch: chain_start_epoch,
+        leafVersion: scheme.leaf_version as u8,
+        treeHash: scheme.tree_hash as u8,
+        hashedPublicValues: scheme.hashed_public_values,
+        treeDepth: scheme.committed_depth(),
+    };
+    sp1_zkvm::io::commit_slice(&AggregationPublicValues::abi_encode(&output));
 }
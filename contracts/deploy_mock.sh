@@ -0,0 +1,20 @@
+#!/bin/bash
+# Deploys SP1MockVerifier and an SP1AggregateVerifier that uses it to a local anvil node.
+# Mock proofs produced with PROVER_BACKEND=mock are accepted by this deployment.
+set -e
+
+RPC_URL=${ANVIL_RPC_URL:-http://localhost:8545}
+# First default anvil account
+PRIVATE_KEY=${WALLET_PRIVATE_KEY:-0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80}
+PROGRAM_VKEY=${PROGRAM_VKEY:?PROGRAM_VKEY must be set}
+SELF_VKEY=${SELF_VKEY:?SELF_VKEY must be set}
+
+MOCK_VERIFIER=$(forge create lib/sp1-contracts/contracts/src/SP1MockVerifier.sol:SP1MockVerifier \
+    --rpc-url $RPC_URL --private-key $PRIVATE_KEY --json | jq -r .deployedTo)
+echo "SP1MockVerifier deployed to $MOCK_VERIFIER"
+
+AGGREGATE_VERIFIER=$(forge create src/SP1AggregateVerifier.sol:SP1AggregateVerifier \
+    --rpc-url $RPC_URL --private-key $PRIVATE_KEY --json \
+    --constructor-args $MOCK_VERIFIER $PROGRAM_VKEY $SELF_VKEY | jq -r .deployedTo)
+echo "SP1AggregateVerifier deployed to $AGGREGATE_VERIFIER"
+echo "Set SP1_AGGREGATE_VERIFIER_CONTRACT_ADDRESS=$AGGREGATE_VERIFIER and CURRENT_CHAIN_ID=31337"
@@ -3,26 +3,78 @@ pragma solidity ^0.8.20;
 
 import {ISP1VerifierGateway} from "@sp1-contracts/ISP1VerifierGateway.sol";
 import "@openzeppelin/contracts/access/Ownable.sol";
+import {MerkleProof} from "@openzeppelin/contracts/utils/cryptography/MerkleProof.sol";
 
 /// @title SP1 Merkle Root Verifier
 /// @notice This contract verifies SP1 proofs and manages a merkle root.
 contract SP1AggregateVerifier is Ownable {
+    /// @notice The public values committed by the aggregate program
+    struct AggregationPublicValues {
+        uint64 epoch;
+        uint64 leafCount;
+        bytes32 prevRoot;
+        bytes32 newRoot;
+        bytes32 prevAccumulatorRoot;
+        bytes32 accumulatorRoot;
+        uint64 accumulatorLeafCount;
+        bytes32 selfVkey;
+        bytes32 chainDigest;
+        uint64 chainStartEpoch;
+        uint8 leafVersion;
+        uint8 treeHash;
+        bool hashedPublicValues;
+        uint8 treeDepth;
+    }
+
     /// @notice The address of the SP1 verifier gateway contract
     ISP1VerifierGateway public verifier;
     /// @notice The verification key for the aggregate program
     bytes32 public immutable PROGRAM_VKEY;
+    /// @notice The same vkey as the program commits it in `selfVkey`, the little endian bytes of its hash_u32 words
+    bytes32 public immutable SELF_VKEY;
     /// @notice The current merkle root of all verified proofs
     bytes32 public merkleRoot;
+    /// @notice The epoch of the current merkle root
+    uint64 public lastEpoch;
+    /// @notice The root of the accumulator over the leaves of every epoch
+    bytes32 public accumulatorRoot;
+    /// @notice The number of leaves in the accumulator
+    uint64 public accumulatorLeafCount;
+    /// @notice The digest of the chain of aggregates the latest one verified in the program
+    bytes32 public chainDigest;
+    /// @notice The leaf encoding of the roots, fixed by the first aggregate
+    uint8 public leafVersion;
+    /// @notice The hash function of the roots, fixed by the first aggregate (0 sha256, 1 keccak256)
+    uint8 public treeHash;
+    /// @notice Whether leaves commit sha256 of the (salted) public values, fixed by the first aggregate
+    bool public hashedPublicValues;
+    /// @notice The fixed depth of padded roots, 0 for unpadded roots, fixed by the first aggregate
+    uint8 public treeDepth;
+
+    uint8 internal constant TREE_HASH_KECCAK = 1;
+    bytes1 internal constant LEAF_TAG = 0x00;
 
-    event MerkleRootUpdated(bytes32 oldRoot, bytes32 newRoot);
+    event MerkleRootUpdated(uint64 indexed epoch, bytes32 oldRoot, bytes32 newRoot, uint64 leafCount);
+    event AccumulatorUpdated(uint64 indexed epoch, bytes32 accumulatorRoot, uint64 leafCount);
 
-    constructor(address _verifierGateway, bytes32 _programVKey) Ownable(msg.sender) {
+    error SelfVkeyMismatch(bytes32 selfVkey, bytes32 expectedSelfVkey);
+    error EpochNotNewer(uint64 epoch, uint64 lastEpoch);
+    error PrevRootMismatch(bytes32 prevRoot, bytes32 merkleRoot);
+    error PrevAccumulatorRootMismatch(bytes32 prevAccumulatorRoot, bytes32 accumulatorRoot);
+    error LeafVersionChanged(uint8 leafVersion, uint8 expectedLeafVersion);
+    error TreeHashChanged(uint8 treeHash, uint8 expectedTreeHash);
+    error HashedPublicValuesChanged(bool hashedPublicValues, bool expectedHashedPublicValues);
+    error TreeDepthChanged(uint8 treeDepth, uint8 expectedTreeDepth);
+    error NotKeccakTree();
+
+    constructor(address _verifierGateway, bytes32 _programVKey, bytes32 _selfVKey) Ownable(msg.sender) {
         verifier = ISP1VerifierGateway(_verifierGateway);
         PROGRAM_VKEY = _programVKey;
+        SELF_VKEY = _selfVKey;
     }
 
     /// @notice Verifies an aggregate proof and updates the merkle root
-    /// @param _publicValues The new merkle root
+    /// @param _publicValues The encoded AggregationPublicValues of the epoch
     /// @param _proofBytes The encoded aggregate proof
     function verifyAggregateProofAndUpdateRoot(
         bytes calldata _publicValues,
@@ -34,8 +86,95 @@ contract SP1AggregateVerifier is Ownable {
             _proofBytes
         );
         
+        AggregationPublicValues memory values = abi.decode(_publicValues, (AggregationPublicValues));
+        // The program takes its own vkey as an input, only a pinned one makes the chain mean anything
+        if (values.selfVkey != SELF_VKEY) {
+            revert SelfVkeyMismatch(values.selfVkey, SELF_VKEY);
+        }
+        if (merkleRoot != bytes32(0) && values.epoch <= lastEpoch) {
+            revert EpochNotNewer(values.epoch, lastEpoch);
+        }
+        if (merkleRoot != bytes32(0) && values.leafVersion != leafVersion) {
+            revert LeafVersionChanged(values.leafVersion, leafVersion);
+        }
+        if (merkleRoot != bytes32(0) && values.treeHash != treeHash) {
+            revert TreeHashChanged(values.treeHash, treeHash);
+        }
+        if (merkleRoot != bytes32(0) && values.hashedPublicValues != hashedPublicValues) {
+            revert HashedPublicValuesChanged(values.hashedPublicValues, hashedPublicValues);
+        }
+        if (merkleRoot != bytes32(0) && values.treeDepth != treeDepth) {
+            revert TreeDepthChanged(values.treeDepth, treeDepth);
+        }
+        if (values.prevRoot != merkleRoot) {
+            revert PrevRootMismatch(values.prevRoot, merkleRoot);
+        }
+        if (values.prevAccumulatorRoot != accumulatorRoot) {
+            revert PrevAccumulatorRootMismatch(values.prevAccumulatorRoot, accumulatorRoot);
+        }
+
         bytes32 oldRoot = merkleRoot;
-        merkleRoot = abi.decode(_publicValues, (bytes32));
-        emit MerkleRootUpdated(oldRoot, merkleRoot);
+        merkleRoot = values.newRoot;
+        lastEpoch = values.epoch;
+        accumulatorRoot = values.accumulatorRoot;
+        accumulatorLeafCount = values.accumulatorLeafCount;
+        chainDigest = values.chainDigest;
+        leafVersion = values.leafVersion;
+        treeHash = values.treeHash;
+        hashedPublicValues = values.hashedPublicValues;
+        treeDepth = values.treeDepth;
+        emit MerkleRootUpdated(values.epoch, oldRoot, values.newRoot, values.leafCount);
+        emit AccumulatorUpdated(values.epoch, values.accumulatorRoot, values.accumulatorLeafCount);
+    }
+
+    /// @notice Computes the double hashed keccak leaf of a submission with the current leaf version
+    /// @param vkey The vkey of the submitted proof's program, the little endian bytes of its hash_u32 words
+    /// @param committedPublicValues The public values, or their (salted) sha256 digest when `hashedPublicValues` is set
+    /// @param submitterIdHash The keccak256 of the submitting Kinode ID, only part of version 2 leaves
+    /// @param submitterNonce The submission nonce of the node, only part of version 2 leaves
+    function leafHash(
+        bytes32 vkey,
+        bytes calldata committedPublicValues,
+        bytes32 submitterIdHash,
+        uint64 submitterNonce
+    ) public view returns (bytes32) {
+        bytes memory preimage;
+        if (leafVersion == 0) {
+            preimage = abi.encodePacked(vkey, committedPublicValues);
+        } else if (leafVersion == 1) {
+            preimage = abi.encodePacked(LEAF_TAG, vkey, uint64(committedPublicValues.length), committedPublicValues);
+        } else {
+            preimage = abi.encodePacked(
+                LEAF_TAG,
+                vkey,
+                uint64(committedPublicValues.length),
+                committedPublicValues,
+                submitterIdHash,
+                submitterNonce
+            );
+        }
+        return keccak256(bytes.concat(keccak256(preimage)));
+    }
+
+    /// @notice Checks that a submission is in the current merkle root
+    /// @dev Only keccak trees hash pairs the way MerkleProof does. The leaf is computed here, since any
+    /// internal node with the rest of its path would pass for a caller supplied leaf.
+    /// @param proof The sibling hashes from the leaf up to the root
+    /// @param vkey The vkey of the submitted proof's program, as in `leafHash`
+    /// @param committedPublicValues The public values the leaf commits, as in `leafHash`
+    /// @param submitterIdHash The keccak256 of the submitting Kinode ID, ignored before version 2
+    /// @param submitterNonce The submission nonce of the node, ignored before version 2
+    function verifyInclusion(
+        bytes32[] calldata proof,
+        bytes32 vkey,
+        bytes calldata committedPublicValues,
+        bytes32 submitterIdHash,
+        uint64 submitterNonce
+    ) external view returns (bool) {
+        if (treeHash != TREE_HASH_KECCAK) {
+            revert NotKeccakTree();
+        }
+        bytes32 leaf = leafHash(vkey, committedPublicValues, submitterIdHash, submitterNonce);
+        return MerkleProof.verifyCalldata(proof, merkleRoot, leaf);
     }
 }
@@ -5,6 +5,7 @@ import {Test, console} from "forge-std/Test.sol";
 import {stdJson} from "forge-std/StdJson.sol";
 import {SP1AggregateVerifier} from "../src/SP1AggregateVerifier.sol";
 import {SP1VerifierGateway} from "@sp1-contracts/SP1VerifierGateway.sol";
+import {SP1MockVerifier} from "@sp1-contracts/SP1MockVerifier.sol";
 
 struct SP1ProofFixtureJson {
     bytes32 verification_key;
@@ -12,12 +13,14 @@ struct SP1ProofFixtureJson {
     bytes proof;
 }
 
-contract SP1AggregateVerifierTest is Test {
+/// @notice Fixture loading and public values shared by the tests below.
+abstract contract SP1AggregateVerifierBase is Test {
     using stdJson for string;
 
-    address verifier;
     SP1AggregateVerifier public aggregateVerifier;
 
+    bytes32 internal constant SELF_VKEY = keccak256("self");
+
     function loadFixture() public view returns (SP1ProofFixtureJson memory) {
         string memory root = vm.projectRoot();
         string memory path = string.concat(root, "/src/fixtures/groth16-onchain-abi.json");
@@ -26,10 +29,62 @@ contract SP1AggregateVerifierTest is Test {
         return abi.decode(jsonBytes, (SP1ProofFixtureJson));
     }
 
+    function encodeValues(uint64 epoch, bytes32 prevRoot, bytes32 newRoot) internal pure returns (bytes memory) {
+        return abi.encode(valuesOf(epoch, prevRoot, newRoot));
+    }
+
+    function encodeValues(uint64 epoch, bytes32 prevRoot, bytes32 newRoot, bytes32 prevAccumulatorRoot)
+        internal
+        pure
+        returns (bytes memory)
+    {
+        return abi.encode(valuesOf(epoch, prevRoot, newRoot, prevAccumulatorRoot));
+    }
+
+    function valuesOf(uint64 epoch, bytes32 prevRoot, bytes32 newRoot)
+        internal
+        pure
+        returns (SP1AggregateVerifier.AggregationPublicValues memory)
+    {
+        return valuesOf(epoch, prevRoot, newRoot, accumulatorOf(prevRoot));
+    }
+
+    /// @dev Every epoch appends 3 leaves, `accumulatorOf` stands in for the real accumulator root.
+    function valuesOf(uint64 epoch, bytes32 prevRoot, bytes32 newRoot, bytes32 prevAccumulatorRoot)
+        internal
+        pure
+        returns (SP1AggregateVerifier.AggregationPublicValues memory)
+    {
+        return SP1AggregateVerifier.AggregationPublicValues(
+            epoch,
+            3,
+            prevRoot,
+            newRoot,
+            prevAccumulatorRoot,
+            accumulatorOf(newRoot),
+            3 * (epoch + 1),
+            SELF_VKEY,
+            keccak256(abi.encode(epoch)),
+            epoch,
+            1,
+            0,
+            false,
+            0
+        );
+    }
+
+    function accumulatorOf(bytes32 root) internal pure returns (bytes32) {
+        return root == bytes32(0) ? bytes32(0) : keccak256(abi.encode(root));
+    }
+}
+
+contract SP1AggregateVerifierTest is SP1AggregateVerifierBase {
+    address verifier;
+
     function setUp() public {
         SP1ProofFixtureJson memory fixture = loadFixture();
         verifier = address(new SP1VerifierGateway(address(1)));
-        aggregateVerifier = new SP1AggregateVerifier(verifier, fixture.verification_key);
+        aggregateVerifier = new SP1AggregateVerifier(verifier, fixture.verification_key, SELF_VKEY);
     }
 
     function test_ValidAggregateProof() public {
@@ -41,25 +96,276 @@ contract SP1AggregateVerifierTest is Test {
             abi.encode(true)
         );
 
-        bytes memory encodedRoot = abi.encode(fixture.merkle_root);
+        bytes memory encodedValues = encodeValues(0, bytes32(0), fixture.merkle_root);
 
         aggregateVerifier.verifyAggregateProofAndUpdateRoot(
-            encodedRoot,
+            encodedValues,
             fixture.proof
         );
 
         assert(aggregateVerifier.merkleRoot() == fixture.merkle_root);
+        assert(aggregateVerifier.lastEpoch() == 0);
     }
 
     function testFail_InvalidAggregateProof() public {
         SP1ProofFixtureJson memory fixture = loadFixture();
         
         bytes memory fakeProof = new bytes(fixture.proof.length);
-        bytes memory encodedRoot = abi.encode(fixture.merkle_root);
+        bytes memory encodedValues = encodeValues(0, bytes32(0), fixture.merkle_root);
 
         aggregateVerifier.verifyAggregateProofAndUpdateRoot(
-            encodedRoot,
+            encodedValues,
             fakeProof
         );
     }
 }
+
+/// @notice Mirrors the offline setup from `deploy_mock.sh`: mock proofs from the
+/// extension's `mock` backend have empty proof bytes and are accepted by `SP1MockVerifier`.
+contract SP1AggregateVerifierMockTest is SP1AggregateVerifierBase {
+    function setUp() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+        address verifier = address(new SP1MockVerifier());
+        aggregateVerifier = new SP1AggregateVerifier(verifier, fixture.verification_key, SELF_VKEY);
+    }
+
+    function test_MockProofUpdatesRoot() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        bytes memory encodedValues = encodeValues(0, bytes32(0), fixture.merkle_root);
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodedValues,
+            new bytes(0)
+        );
+
+        assert(aggregateVerifier.merkleRoot() == fixture.merkle_root);
+    }
+
+    function test_MockProofChainsEpochs() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+        bytes32 nextRoot = keccak256("next");
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(1, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(2, fixture.merkle_root, nextRoot),
+            new bytes(0)
+        );
+
+        assert(aggregateVerifier.merkleRoot() == nextRoot);
+        assert(aggregateVerifier.lastEpoch() == 2);
+        assert(aggregateVerifier.accumulatorRoot() == accumulatorOf(nextRoot));
+        assert(aggregateVerifier.accumulatorLeafCount() == 9);
+        assert(aggregateVerifier.chainDigest() == keccak256(abi.encode(uint64(2))));
+        assert(aggregateVerifier.leafVersion() == 1);
+        assert(aggregateVerifier.treeHash() == 0);
+        assert(!aggregateVerifier.hashedPublicValues());
+    }
+
+    function test_RevertWhen_EpochNotNewer() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(2, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+
+        vm.expectRevert(
+            abi.encodeWithSelector(SP1AggregateVerifier.EpochNotNewer.selector, uint64(2), uint64(2))
+        );
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(2, fixture.merkle_root, keccak256("next")),
+            new bytes(0)
+        );
+    }
+
+    function test_RevertWhen_PrevRootMismatch() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(1, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+
+        bytes32 staleRoot = keccak256("stale");
+        vm.expectRevert(
+            abi.encodeWithSelector(
+                SP1AggregateVerifier.PrevRootMismatch.selector, staleRoot, fixture.merkle_root
+            )
+        );
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(2, staleRoot, keccak256("next")),
+            new bytes(0)
+        );
+    }
+
+    function testFail_MockVerifierRejectsRealProofBytes() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        bytes memory encodedValues = encodeValues(0, bytes32(0), fixture.merkle_root);
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodedValues,
+            fixture.proof
+        );
+    }
+
+    function test_RevertWhen_PrevAccumulatorRootMismatch() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(1, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+
+        bytes32 staleAccumulator = keccak256("stale");
+        vm.expectRevert(
+            abi.encodeWithSelector(
+                SP1AggregateVerifier.PrevAccumulatorRootMismatch.selector,
+                staleAccumulator,
+                accumulatorOf(fixture.merkle_root)
+            )
+        );
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(2, fixture.merkle_root, keccak256("next"), staleAccumulator),
+            new bytes(0)
+        );
+    }
+
+    function test_RevertWhen_SelfVkeyMismatch() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+        bytes32 otherVkey = keccak256("other");
+
+        SP1AggregateVerifier.AggregationPublicValues memory values = valuesOf(1, bytes32(0), fixture.merkle_root);
+        values.selfVkey = otherVkey;
+        expectRevertOf(
+            values, abi.encodeWithSelector(SP1AggregateVerifier.SelfVkeyMismatch.selector, otherVkey, SELF_VKEY)
+        );
+    }
+
+    /// @dev The scheme is fixed by the first aggregate, changing any part of it reverts.
+    function test_RevertWhen_SchemeChanged() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(1, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+
+        SP1AggregateVerifier.AggregationPublicValues memory values = nextValues(fixture);
+        values.leafVersion = 0;
+        expectRevertOf(values, abi.encodeWithSelector(SP1AggregateVerifier.LeafVersionChanged.selector, uint8(0), uint8(1)));
+
+        values = nextValues(fixture);
+        values.treeHash = 1;
+        expectRevertOf(values, abi.encodeWithSelector(SP1AggregateVerifier.TreeHashChanged.selector, uint8(1), uint8(0)));
+
+        values = nextValues(fixture);
+        values.hashedPublicValues = true;
+        expectRevertOf(
+            values, abi.encodeWithSelector(SP1AggregateVerifier.HashedPublicValuesChanged.selector, true, false)
+        );
+
+        values = nextValues(fixture);
+        values.treeDepth = 20;
+        expectRevertOf(values, abi.encodeWithSelector(SP1AggregateVerifier.TreeDepthChanged.selector, uint8(20), uint8(0)));
+    }
+
+    /// @dev Values of epoch 2 that extend epoch 1 of the fixture.
+    function nextValues(SP1ProofFixtureJson memory fixture)
+        internal
+        pure
+        returns (SP1AggregateVerifier.AggregationPublicValues memory)
+    {
+        return valuesOf(2, fixture.merkle_root, keccak256("next"));
+    }
+
+    function expectRevertOf(SP1AggregateVerifier.AggregationPublicValues memory values, bytes memory revertData)
+        internal
+    {
+        vm.expectRevert(revertData);
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(abi.encode(values), new bytes(0));
+    }
+
+    function hashPair(bytes32 a, bytes32 b) internal pure returns (bytes32) {
+        return a < b ? keccak256(abi.encode(a, b)) : keccak256(abi.encode(b, a));
+    }
+
+    bytes32 internal constant LEAF_VKEY = bytes32(0x1111111111111111111111111111111111111111111111111111111111111111);
+
+    /// @dev A version 1 keccak leaf of `LEAF_VKEY`, as `Scheme::leaf_hash` builds it.
+    function leafOf(bytes memory publicValues) internal pure returns (bytes32) {
+        return keccak256(
+            bytes.concat(keccak256(abi.encodePacked(bytes1(0x00), LEAF_VKEY, uint64(publicValues.length), publicValues)))
+        );
+    }
+
+    /// @dev Starts a keccak tree with the given leaf version and root.
+    function startKeccakTree(uint8 leafVersion, bytes32 root) internal {
+        SP1AggregateVerifier.AggregationPublicValues memory values = valuesOf(1, bytes32(0), root);
+        values.leafVersion = leafVersion;
+        values.treeHash = 1;
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(abi.encode(values), new bytes(0));
+    }
+
+    /// @dev Three leaves as the aggregator builds them in keccak mode: the odd leaf is carried up.
+    function test_KeccakTreeVerifiesInclusion() public {
+        bytes32 a = leafOf("a");
+        bytes32 b = leafOf("b");
+        bytes32 c = leafOf("c");
+        startKeccakTree(1, hashPair(hashPair(a, b), c));
+
+        bytes32[] memory proofA = new bytes32[](2);
+        proofA[0] = b;
+        proofA[1] = c;
+        bytes32[] memory proofC = new bytes32[](1);
+        proofC[0] = hashPair(a, b);
+
+        assert(aggregateVerifier.verifyInclusion(proofA, LEAF_VKEY, "a", bytes32(0), 0));
+        assert(aggregateVerifier.verifyInclusion(proofC, LEAF_VKEY, "c", bytes32(0), 0));
+        assert(!aggregateVerifier.verifyInclusion(proofC, LEAF_VKEY, "a", bytes32(0), 0));
+    }
+
+    /// @dev The pair above `a` and `b` with the path of `c` leads to the root, but it is no leaf.
+    function test_InternalNodeDoesNotVerify() public {
+        bytes32 a = leafOf("a");
+        bytes32 b = leafOf("b");
+        bytes32 c = leafOf("c");
+        startKeccakTree(1, hashPair(hashPair(a, b), c));
+
+        bytes32[] memory proof = new bytes32[](1);
+        proof[0] = c;
+
+        assert(!aggregateVerifier.verifyInclusion(proof, LEAF_VKEY, abi.encodePacked(hashPair(a, b)), bytes32(0), 0));
+    }
+
+    /// @dev Same vectors as `keccak_leaves_match_the_contract` in shared_merkle.
+    function test_LeafHashMatchesScheme() public {
+        bytes32[3] memory expected = [
+            bytes32(0xf5103f65d798c61025745a640fba41ac2f91745c5af3442fb73063c127c8d1b9),
+            bytes32(0xbe6bd5cea9d2ef820b7b7f059a52ba95a357808a564be8ad7857f1c7c416e30f),
+            bytes32(0x17ef79fe9c45ef2a781736ddabc87aa9592cafe811528901c994e009e0f57756)
+        ];
+        bytes32 submitterIdHash = bytes32(0x2222222222222222222222222222222222222222222222222222222222222222);
+        for (uint8 version = 0; version < 3; version++) {
+            aggregateVerifier = new SP1AggregateVerifier(address(new SP1MockVerifier()), bytes32(0), SELF_VKEY);
+            startKeccakTree(version, keccak256("root"));
+            assertEq(aggregateVerifier.leafHash(LEAF_VKEY, "a", submitterIdHash, 7), expected[version]);
+        }
+    }
+
+    function test_RevertWhen_InclusionOnSha256Tree() public {
+        SP1ProofFixtureJson memory fixture = loadFixture();
+
+        aggregateVerifier.verifyAggregateProofAndUpdateRoot(
+            encodeValues(1, bytes32(0), fixture.merkle_root),
+            new bytes(0)
+        );
+
+        vm.expectRevert(SP1AggregateVerifier.NotKeccakTree.selector);
+        aggregateVerifier.verifyInclusion(new bytes32[](0), LEAF_VKEY, "a", bytes32(0), 0);
+    }
+}
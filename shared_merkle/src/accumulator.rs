@@ -0,0 +1,142 @@
+//! Append-only Merkle Mountain Range over the leaves of every aggregated epoch.
+//!
+//! The accumulator is a list of perfect subtrees ("mountains"), one per set bit of
+//! the leaf count and tallest first. Appending a leaf merges equal sized mountains,
+//! so a leaf never moves once it is in and its path only grows by the peaks that
+//! are added after it. Each mountain is a power of two tree built with
+//! [`Scheme::node_hash`], which makes it the same tree [`merkle_root`] builds
+//! over its leaves. One accumulator must always be extended with the same scheme.
+
+use crate::{inclusion_proof, merkle_root, verify_inclusion, Hash, InclusionProof, Scheme};
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Peaks of the accumulator. This is all the state needed to keep appending.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Accumulator {
+    pub leaf_count: u64,
+    /// Mountain roots, tallest first.
+    pub peaks: Vec<Hash>,
+}
+
+impl Accumulator {
+    /// Whether there is exactly one peak per mountain of `leaf_count`.
+    pub fn is_well_formed(&self) -> bool {
+        self.peaks.len() == self.leaf_count.count_ones() as usize
+    }
+
+    pub fn append(&mut self, scheme: Scheme, leaf: Hash) {
+        let mut current = leaf;
+        // Every trailing one of the count is a mountain of the size we are about to complete
+        for _ in 0..self.leaf_count.trailing_ones() {
+            let left = self
+                .peaks
+                .pop()
+                .expect("Accumulator should have a peak per mountain");
+            current = scheme.node_hash(&left, &current);
+        }
+        self.peaks.push(current);
+        self.leaf_count += 1;
+    }
+
+    pub fn extend(&mut self, scheme: Scheme, leaves: &[Hash]) {
+        for leaf in leaves {
+            self.append(scheme, *leaf);
+        }
+    }
+
+    pub fn root(&self, scheme: Scheme) -> Hash {
+        bag_peaks(scheme, self.leaf_count, &self.peaks)
+    }
+}
+
+/// Root over the peaks: `H(leaf_count_be || bagged)`, where the peaks are folded
+/// right to left with [`Scheme::node_hash`]. The empty accumulator has the zero root.
+pub fn bag_peaks(scheme: Scheme, leaf_count: u64, peaks: &[Hash]) -> Hash {
+    let Some(bagged) = peaks
+        .iter()
+        .rev()
+        .copied()
+        .reduce(|right, left| scheme.node_hash(&left, &right))
+    else {
+        return [0u8; 32];
+    };
+    scheme.tree_hash.hash(&[&leaf_count.to_be_bytes(), &bagged])
+}
+
+/// Path from a leaf to the root of its mountain, plus every peak at the time of the proof.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccumulatorProof {
+    pub leaf_index: u64,
+    pub leaf_count: u64,
+    /// Sibling hashes from the leaf up to its mountain root.
+    pub siblings: Vec<Hash>,
+    pub peaks: Vec<Hash>,
+}
+
+/// Proof for the leaf at `index` against the accumulator over all of `leaves`,
+/// `None` if it is out of range.
+pub fn accumulator_proof(scheme: Scheme, leaves: &[Hash], index: u64) -> Option<AccumulatorProof> {
+    let leaf_count = leaves.len() as u64;
+    if index >= leaf_count {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut peaks = Vec::new();
+    for (start, size) in mountains(leaf_count) {
+        let mountain = &leaves[start as usize..(start + size) as usize];
+        if (start..start + size).contains(&index) {
+            siblings = inclusion_proof(scheme, mountain, (index - start) as usize)?.siblings;
+        }
+        peaks.push(merkle_root(scheme, mountain)?);
+    }
+    Some(AccumulatorProof {
+        leaf_index: index,
+        leaf_count,
+        siblings,
+        peaks,
+    })
+}
+
+/// Checks that `leaf` sits at `proof.leaf_index` in an accumulator with the given root.
+pub fn verify_accumulator_inclusion(
+    scheme: Scheme,
+    root: &Hash,
+    leaf: &Hash,
+    proof: &AccumulatorProof,
+) -> bool {
+    if proof.leaf_index >= proof.leaf_count
+        || proof.peaks.len() != proof.leaf_count.count_ones() as usize
+    {
+        return false;
+    }
+    let Some((peak, (start, size))) = proof
+        .peaks
+        .iter()
+        .zip(mountains(proof.leaf_count))
+        .find(|(_, (start, size))| (*start..start + size).contains(&proof.leaf_index))
+    else {
+        return false;
+    };
+    let path = InclusionProof {
+        leaf_index: (proof.leaf_index - start) as usize,
+        leaf_count: size as usize,
+        siblings: proof.siblings.clone(),
+    };
+    verify_inclusion(scheme, peak, leaf, &path)
+        && bag_peaks(scheme, proof.leaf_count, &proof.peaks) == *root
+}
+
+// (first leaf, leaf count) of each mountain, tallest first
+fn mountains(leaf_count: u64) -> impl Iterator<Item = (u64, u64)> {
+    let mut start = 0;
+    (0..u64::BITS)
+        .rev()
+        .filter(move |height| leaf_count >> height & 1 == 1)
+        .map(move |height| {
+            let size = 1u64 << height;
+            let mountain = (start, size);
+            start += size;
+            mountain
+        })
+}
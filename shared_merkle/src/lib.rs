@@ -0,0 +1,301 @@
+//! Merkle commitment over (vkey, public values) pairs, shared by the zkVM guest,
+//! the process, the extension and the tests so they all agree on leaves and roots.
+//!
+//! The tree has the same shape as `rs_merkle::MerkleTree::from_leaves`: nodes are
+//! paired left to right and an odd node at the end of a layer is promoted unchanged.
+//! With a padded depth it is instead a full tree of fixed depth, see [`padded_root`].
+//! How leaves and nodes are hashed depends on the [`Scheme`].
+
+#![no_std]
+extern crate alloc;
+
+pub mod accumulator;
+pub mod public_values;
+mod scheme;
+
+pub use scheme::{
+    public_values_digest, salted_digest, LeafVersion, Scheme, Submitter, TreeHash, LEAF_TAG,
+    NODE_TAG,
+};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use serde::{Deserialize, Serialize};
+
+pub type Hash = [u8; 32];
+
+/// Value of the leaves a padded tree is filled up with.
+pub const EMPTY_LEAF: Hash = [0u8; 32];
+
+/// Deepest padded tree, `2^31` leaves, so that the leaf count of its proofs fits a 32-bit
+/// `usize` in the wasm process. The shallowest is one level, so that a committed depth of 0
+/// can stand for an unpadded tree.
+pub const MAX_PADDED_DEPTH: u8 = 31;
+
+pub fn words_to_bytes_le(words: &[u32; 8]) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, word) in words.iter().enumerate() {
+        bytes[i * 4..(i + 1) * 4].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// Root of the tree over `leaves`, `None` when there are no leaves.
+pub fn merkle_root(scheme: Scheme, leaves: &[Hash]) -> Option<Hash> {
+    let mut layer = leaves.to_vec();
+    while layer.len() > 1 {
+        layer = next_layer(scheme, &layer);
+    }
+    layer.first().copied()
+}
+
+/// Root of the epoch tree over `leaves` in the shape the scheme asks for: [`padded_root`]
+/// with a padded depth, [`merkle_root`] otherwise.
+pub fn tree_root(scheme: Scheme, leaves: &[Hash]) -> Option<Hash> {
+    match scheme.padded_depth {
+        Some(depth) => padded_root(scheme, leaves, depth),
+        None => merkle_root(scheme, leaves),
+    }
+}
+
+/// Inclusion proof in the tree [`tree_root`] builds, checked with [`verify_inclusion`].
+pub fn tree_inclusion_proof(
+    scheme: Scheme,
+    leaves: &[Hash],
+    index: usize,
+) -> Option<InclusionProof> {
+    match scheme.padded_depth {
+        Some(depth) => padded_inclusion_proof(scheme, leaves, index, depth),
+        None => inclusion_proof(scheme, leaves, index),
+    }
+}
+
+/// Root of the full tree of `depth` levels whose first leaves are `leaves` and the rest
+/// [`EMPTY_LEAF`]. Without leaves this is the root of an empty tree. `None` when the
+/// leaves do not fit or `depth` is not in `1..=MAX_PADDED_DEPTH`.
+pub fn padded_root(scheme: Scheme, leaves: &[Hash], depth: u8) -> Option<Hash> {
+    if !fits_padded(leaves.len(), depth) {
+        return None;
+    }
+    let mut layer = leaves.to_vec();
+    let mut empty = EMPTY_LEAF;
+    for _ in 0..depth {
+        layer = next_padded_layer(scheme, &layer, &empty);
+        empty = scheme.node_hash(&empty, &empty);
+    }
+    Some(layer.first().copied().unwrap_or(empty))
+}
+
+/// Inclusion proof for the leaf at `index` in the tree of [`padded_root`]. It always has
+/// `depth` siblings and verifies with [`verify_inclusion`] as a tree of `2^depth` leaves.
+pub fn padded_inclusion_proof(
+    scheme: Scheme,
+    leaves: &[Hash],
+    index: usize,
+    depth: u8,
+) -> Option<InclusionProof> {
+    if index >= leaves.len() || !fits_padded(leaves.len(), depth) {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut layer = leaves.to_vec();
+    let mut empty = EMPTY_LEAF;
+    let mut position = index;
+    for _ in 0..depth {
+        siblings.push(layer.get(position ^ 1).copied().unwrap_or(empty));
+        layer = next_padded_layer(scheme, &layer, &empty);
+        empty = scheme.node_hash(&empty, &empty);
+        position /= 2;
+    }
+    Some(InclusionProof {
+        leaf_index: index,
+        leaf_count: 1 << depth,
+        siblings,
+    })
+}
+
+/// Leaves for a list of vkeys and committed values, `None` when the lists differ in length.
+/// `submitters` may be empty unless the leaf version binds them, `salts` may be empty
+/// when no public values are salted.
+pub fn proof_pair_leaves(
+    scheme: Scheme,
+    vkeys: &[[u32; 8]],
+    committed_values: &[Vec<u8>],
+    submitters: &[Submitter],
+    salts: &[Option<Hash>],
+) -> Option<Vec<Hash>> {
+    if !pairs_line_up(
+        scheme,
+        vkeys.len(),
+        committed_values.len(),
+        submitters,
+        salts,
+    ) {
+        return None;
+    }
+    Some(
+        vkeys
+            .iter()
+            .zip(committed_values.iter())
+            .enumerate()
+            .map(|(index, (vkey, value))| {
+                let submitter = submitters.get(index).copied().unwrap_or_default();
+                let salt = salts.get(index).copied().flatten();
+                let value = scheme.committed_public_values(value, salt.as_ref());
+                scheme.leaf_hash(vkey, &value, &submitter)
+            })
+            .collect(),
+    )
+}
+
+/// Same leaves as [`proof_pair_leaves`] for a scheme with `hashed_public_values`, from the
+/// `sha256` of each proof's public values instead of the values. `None` when the scheme
+/// commits the raw values or the lists differ in length.
+pub fn digest_pair_leaves(
+    scheme: Scheme,
+    vkeys: &[[u32; 8]],
+    digests: &[Hash],
+    submitters: &[Submitter],
+    salts: &[Option<Hash>],
+) -> Option<Vec<Hash>> {
+    if !scheme.hashed_public_values
+        || !pairs_line_up(scheme, vkeys.len(), digests.len(), submitters, salts)
+    {
+        return None;
+    }
+    Some(
+        vkeys
+            .iter()
+            .zip(digests.iter())
+            .enumerate()
+            .map(|(index, (vkey, digest))| {
+                let submitter = submitters.get(index).copied().unwrap_or_default();
+                let salt = salts.get(index).copied().flatten();
+                scheme.leaf_hash(vkey, &salted_digest(digest, salt.as_ref()), &submitter)
+            })
+            .collect(),
+    )
+}
+
+/// First leaf that repeats an earlier one, as `(earlier, repeat)` indices. A batch must not
+/// commit the same leaf twice.
+pub fn first_duplicate(leaves: &[Hash]) -> Option<(usize, usize)> {
+    let mut seen = BTreeMap::new();
+    leaves
+        .iter()
+        .enumerate()
+        .find_map(|(index, leaf)| seen.insert(leaf, index).map(|earlier| (earlier, index)))
+}
+
+/// Encode a list of vkeys and committed values into a single root using a merkle tree.
+/// `None` when the lists differ in length, or are empty and the tree is not padded.
+pub fn commit_proof_pairs(
+    scheme: Scheme,
+    vkeys: &[[u32; 8]],
+    committed_values: &[Vec<u8>],
+    submitters: &[Submitter],
+    salts: &[Option<Hash>],
+) -> Option<Hash> {
+    tree_root(
+        scheme,
+        &proof_pair_leaves(scheme, vkeys, committed_values, submitters, salts)?,
+    )
+}
+
+/// Path from a leaf to the root. Layers where the node was promoted have no sibling.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InclusionProof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    /// Sibling hashes from the bottom layer up.
+    pub siblings: Vec<Hash>,
+}
+
+/// Inclusion proof for the leaf at `index`, `None` if it is out of range.
+pub fn inclusion_proof(scheme: Scheme, leaves: &[Hash], index: usize) -> Option<InclusionProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut layer = leaves.to_vec();
+    let mut position = index;
+    while layer.len() > 1 {
+        if let Some(sibling) = layer.get(position ^ 1) {
+            siblings.push(*sibling);
+        }
+        layer = next_layer(scheme, &layer);
+        position /= 2;
+    }
+    Some(InclusionProof {
+        leaf_index: index,
+        leaf_count: leaves.len(),
+        siblings,
+    })
+}
+
+/// Checks that `leaf` sits at `proof.leaf_index` in a tree with the given root.
+pub fn verify_inclusion(scheme: Scheme, root: &Hash, leaf: &Hash, proof: &InclusionProof) -> bool {
+    if proof.leaf_index >= proof.leaf_count {
+        return false;
+    }
+    let mut siblings = proof.siblings.iter();
+    let mut current = *leaf;
+    let mut position = proof.leaf_index;
+    let mut layer_len = proof.leaf_count;
+    while layer_len > 1 {
+        let sibling_position = position ^ 1;
+        if sibling_position < layer_len {
+            let Some(sibling) = siblings.next() else {
+                return false;
+            };
+            current = if position & 1 == 0 {
+                scheme.node_hash(&current, sibling)
+            } else {
+                scheme.node_hash(sibling, &current)
+            };
+        }
+        position /= 2;
+        layer_len = layer_len.div_ceil(2);
+    }
+    siblings.next().is_none() && current == *root
+}
+
+/// Whether `leaf_count` leaves fit in a padded tree of `depth` levels.
+pub fn fits_padded(leaf_count: usize, depth: u8) -> bool {
+    (1..=MAX_PADDED_DEPTH).contains(&depth) && leaf_count as u64 <= 1u64 << depth
+}
+
+// Every proof has a vkey and its values, and a submitter and a salt where they are used
+fn pairs_line_up(
+    scheme: Scheme,
+    vkey_count: usize,
+    value_count: usize,
+    submitters: &[Submitter],
+    salts: &[Option<Hash>],
+) -> bool {
+    vkey_count == value_count
+        && (!scheme.leaf_version.binds_submitter() || submitters.len() == vkey_count)
+        && (salts.is_empty() || salts.len() == vkey_count)
+}
+
+// Pairs up a layer of a padded tree, an odd node at the end is paired with `empty`
+fn next_padded_layer(scheme: Scheme, layer: &[Hash], empty: &Hash) -> Vec<Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => scheme.node_hash(left, right),
+            [single] => scheme.node_hash(single, empty),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn next_layer(scheme: Scheme, layer: &[Hash]) -> Vec<Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => scheme.node_hash(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
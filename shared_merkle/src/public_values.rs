@@ -0,0 +1,42 @@
+//! Public output of the aggregator program. It is ABI encoded so that
+//! `SP1AggregateVerifier` can `abi.decode` it into the same struct.
+
+use alloy_sol_types::sol;
+use sha2::{Digest, Sha256};
+
+sol! {
+    /// Committed by the aggregator program for every epoch.
+    struct AggregationPublicValues {
+        uint64 epoch;
+        uint64 leafCount;
+        bytes32 prevRoot;
+        bytes32 newRoot;
+        bytes32 prevAccumulatorRoot;
+        bytes32 accumulatorRoot;
+        uint64 accumulatorLeafCount;
+        bytes32 selfVkey;
+        bytes32 chainDigest;
+        uint64 chainStartEpoch;
+        uint8 leafVersion;
+        uint8 treeHash;
+        bool hashedPublicValues;
+        uint8 treeDepth;
+    }
+}
+
+/// Digest of the chain of aggregates up to `epoch`:
+/// `sha256(prev_chain_digest || epoch_be || new_root || accumulator_root)`.
+/// The first aggregate of a chain uses a zero `prev_chain_digest`.
+pub fn chain_digest(
+    prev_chain_digest: &[u8; 32],
+    epoch: u64,
+    new_root: &[u8; 32],
+    accumulator_root: &[u8; 32],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_chain_digest);
+    hasher.update(epoch.to_be_bytes());
+    hasher.update(new_root);
+    hasher.update(accumulator_root);
+    hasher.finalize().into()
+}
@@ -0,0 +1,271 @@
+//! How leaves and internal nodes are hashed. The aggregate commits every part of
+//! the [`Scheme`] so verifiers know how to check its roots.
+
+use crate::{words_to_bytes_le, Hash};
+use alloc::{borrow::Cow, format, string::String};
+use core::str::FromStr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tiny_keccak::{Hasher, Keccak};
+
+/// Domain tag of a leaf hash from [`LeafVersion::V1`] on.
+pub const LEAF_TAG: u8 = 0x00;
+/// Domain tag of an internal node hash from [`LeafVersion::V1`] on.
+pub const NODE_TAG: u8 = 0x01;
+
+/// Encoding of leaves and internal nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum LeafVersion {
+    /// `H(vkey_bytes || public_values)` leaves and `H(left || right)` nodes, the
+    /// unversioned format. A leaf over 32 bytes of public values can pass for a node.
+    #[default]
+    V0 = 0,
+    /// `H(0x00 || vkey_bytes || len_be || public_values)` leaves, with the length of
+    /// the public values as a big endian u64, and `H(0x01 || left || right)` nodes.
+    V1 = 1,
+    /// [`LeafVersion::V1`] leaves that also commit who submitted the proof:
+    /// `H(0x00 || vkey_bytes || len_be || public_values || submitter_id_hash || nonce_be)`.
+    /// Nodes are the same as in V1.
+    V2 = 2,
+}
+
+impl LeafVersion {
+    /// Whether leaves commit their [`Submitter`].
+    pub fn binds_submitter(self) -> bool {
+        matches!(self, LeafVersion::V2)
+    }
+}
+
+/// Who submitted a proof, committed by [`LeafVersion::V2`] leaves so that two
+/// submissions with the same public values can be told apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Submitter {
+    /// Tree hash of the submitting node's Kinode ID.
+    pub id_hash: Hash,
+    /// Number of earlier submissions from the same node.
+    pub nonce: u64,
+}
+
+/// Hash function of the tree and how pairs are ordered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum TreeHash {
+    /// SHA-256 over left/right ordered pairs, the layout of `rs_merkle`.
+    #[default]
+    Sha256 = 0,
+    /// keccak256 over sorted pairs with double hashed leaves, the layout OpenZeppelin's
+    /// `MerkleProof` verifies. Node hashes carry no domain tag in this mode: the double
+    /// hashing already keeps leaves and nodes apart. A proof shows membership but not
+    /// the position of the leaf.
+    Keccak = 1,
+}
+
+impl TreeHash {
+    /// Hash of the concatenation of `parts`.
+    pub fn hash(self, parts: &[&[u8]]) -> Hash {
+        let mut digest = [0u8; 32];
+        match self {
+            TreeHash::Sha256 => {
+                let mut hasher = Sha256::new();
+                for part in parts {
+                    hasher.update(part);
+                }
+                digest = hasher.finalize().into();
+            }
+            TreeHash::Keccak => {
+                let mut hasher = Keccak::v256();
+                for part in parts {
+                    hasher.update(part);
+                }
+                hasher.finalize(&mut digest);
+            }
+        }
+        digest
+    }
+}
+
+/// Everything that decides how leaves and nodes are hashed. A missing part means
+/// the original format, so serialized jobs and state from before it still read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Scheme {
+    #[serde(default)]
+    pub leaf_version: LeafVersion,
+    #[serde(default)]
+    pub tree_hash: TreeHash,
+    /// Leaves commit [`public_values_digest`] instead of the raw public values, so
+    /// inclusion can be shown without them.
+    #[serde(default)]
+    pub hashed_public_values: bool,
+    /// Epoch trees have exactly this many levels, padded with [`crate::EMPTY_LEAF`],
+    /// instead of the `rs_merkle` shape that depends on the leaf count.
+    #[serde(default)]
+    pub padded_depth: Option<u8>,
+}
+
+/// `sha256(public_values)`, or `sha256(salt || sha256(public_values))` when the
+/// submitter blinds it with a salt.
+pub fn public_values_digest(public_values: &[u8], salt: Option<&Hash>) -> Hash {
+    salted_digest(&Sha256::digest(public_values).into(), salt)
+}
+
+/// [`public_values_digest`] from `sha256(public_values)`, the digest proofs are verified
+/// with, so the public values need not be hashed again.
+pub fn salted_digest(digest: &Hash, salt: Option<&Hash>) -> Hash {
+    match salt {
+        Some(salt) => TreeHash::Sha256.hash(&[salt, digest]),
+        None => *digest,
+    }
+}
+
+impl Scheme {
+    pub const fn new(leaf_version: LeafVersion, tree_hash: TreeHash) -> Self {
+        Self {
+            leaf_version,
+            tree_hash,
+            hashed_public_values: false,
+            padded_depth: None,
+        }
+    }
+
+    pub const fn with_hashed_public_values(mut self, hashed_public_values: bool) -> Self {
+        self.hashed_public_values = hashed_public_values;
+        self
+    }
+
+    pub const fn with_padded_depth(mut self, padded_depth: Option<u8>) -> Self {
+        self.padded_depth = padded_depth;
+        self
+    }
+
+    /// Depth the aggregate commits, 0 when the tree is not padded.
+    pub fn committed_depth(self) -> u8 {
+        self.padded_depth.unwrap_or(0)
+    }
+
+    /// What a leaf commits for `public_values`: the values themselves, or their digest
+    /// with `hashed_public_values`. The salt is only used for the digest.
+    pub fn committed_public_values<'a>(
+        self,
+        public_values: &'a [u8],
+        salt: Option<&Hash>,
+    ) -> Cow<'a, [u8]> {
+        if self.hashed_public_values {
+            Cow::Owned(public_values_digest(public_values, salt).to_vec())
+        } else {
+            Cow::Borrowed(public_values)
+        }
+    }
+
+    /// Submitter with the given Kinode ID and nonce.
+    pub fn submitter(self, kinode_id: &str, nonce: u64) -> Submitter {
+        Submitter {
+            id_hash: self.tree_hash.hash(&[kinode_id.as_bytes()]),
+            nonce,
+        }
+    }
+
+    /// Leaf for a proof of the program with `vkey` that committed `public_values`, as
+    /// returned by [`Scheme::committed_public_values`]. The submitter is only part of the
+    /// leaf if the version binds it.
+    pub fn leaf_hash(self, vkey: &[u32; 8], public_values: &[u8], submitter: &Submitter) -> Hash {
+        self.leaf_hash_from_bytes(&words_to_bytes_le(vkey), public_values, submitter)
+    }
+
+    /// Same as [`Scheme::leaf_hash`] for a vkey already in its little-endian byte form.
+    pub fn leaf_hash_from_bytes(
+        self,
+        vkey_bytes: &[u8; 32],
+        public_values: &[u8],
+        submitter: &Submitter,
+    ) -> Hash {
+        let length = (public_values.len() as u64).to_be_bytes();
+        let leaf = match self.leaf_version {
+            LeafVersion::V0 => self.tree_hash.hash(&[vkey_bytes, public_values]),
+            LeafVersion::V1 => {
+                self.tree_hash
+                    .hash(&[&[LEAF_TAG], vkey_bytes, &length, public_values])
+            }
+            LeafVersion::V2 => self.tree_hash.hash(&[
+                &[LEAF_TAG],
+                vkey_bytes,
+                &length,
+                public_values,
+                &submitter.id_hash,
+                &submitter.nonce.to_be_bytes(),
+            ]),
+        };
+        match self.tree_hash {
+            TreeHash::Sha256 => leaf,
+            TreeHash::Keccak => self.tree_hash.hash(&[&leaf]),
+        }
+    }
+
+    pub fn node_hash(self, left: &Hash, right: &Hash) -> Hash {
+        match (self.tree_hash, self.leaf_version) {
+            (TreeHash::Keccak, _) => {
+                // OpenZeppelin's `_hashPair`
+                let (low, high) = if left <= right {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                self.tree_hash.hash(&[low, high])
+            }
+            (TreeHash::Sha256, LeafVersion::V0) => self.tree_hash.hash(&[left, right]),
+            (TreeHash::Sha256, LeafVersion::V1 | LeafVersion::V2) => {
+                self.tree_hash.hash(&[&[NODE_TAG], left, right])
+            }
+        }
+    }
+}
+
+impl TryFrom<u8> for LeafVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            0 => Ok(LeafVersion::V0),
+            1 => Ok(LeafVersion::V1),
+            2 => Ok(LeafVersion::V2),
+            other => Err(format!("Unknown leaf version: {}", other)),
+        }
+    }
+}
+
+impl FromStr for LeafVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "v0" | "0" => Ok(LeafVersion::V0),
+            "v1" | "1" => Ok(LeafVersion::V1),
+            "v2" | "2" => Ok(LeafVersion::V2),
+            other => Err(format!("Unknown leaf version: {}", other)),
+        }
+    }
+}
+
+impl TryFrom<u8> for TreeHash {
+    type Error = String;
+
+    fn try_from(tree_hash: u8) -> Result<Self, Self::Error> {
+        match tree_hash {
+            0 => Ok(TreeHash::Sha256),
+            1 => Ok(TreeHash::Keccak),
+            other => Err(format!("Unknown tree hash: {}", other)),
+        }
+    }
+}
+
+impl FromStr for TreeHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "sha256" => Ok(TreeHash::Sha256),
+            "keccak" | "keccak256" => Ok(TreeHash::Keccak),
+            other => Err(format!("Unknown tree hash: {}", other)),
+        }
+    }
+}
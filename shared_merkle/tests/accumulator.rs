@@ -0,0 +1,129 @@
+//! The accumulator keeps every earlier leaf provable against the latest root,
+//! no matter how the leaves were split across epochs.
+
+use shared_merkle::accumulator::{
+    accumulator_proof, bag_peaks, verify_accumulator_inclusion, Accumulator,
+};
+use shared_merkle::{merkle_root, Hash, LeafVersion, Scheme, Submitter, TreeHash};
+
+const SCHEME: Scheme = Scheme::new(LeafVersion::V1, TreeHash::Sha256);
+
+fn sample_leaves(count: usize) -> Vec<Hash> {
+    (0..count)
+        .map(|i| {
+            SCHEME.leaf_hash(
+                &[i as u32; 8],
+                &(i as u64).to_le_bytes(),
+                &Submitter::default(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn empty_accumulator_has_zero_root() {
+    let accumulator = Accumulator::default();
+    assert!(accumulator.is_well_formed());
+    assert_eq!(accumulator.root(SCHEME), [0u8; 32]);
+}
+
+#[test]
+fn peaks_are_roots_of_power_of_two_mountains() {
+    let leaves = sample_leaves(13);
+    let mut accumulator = Accumulator::default();
+    accumulator.extend(SCHEME, &leaves);
+
+    // 13 = 8 + 4 + 1
+    assert!(accumulator.is_well_formed());
+    assert_eq!(
+        accumulator.peaks,
+        vec![
+            merkle_root(SCHEME, &leaves[0..8]).unwrap(),
+            merkle_root(SCHEME, &leaves[8..12]).unwrap(),
+            leaves[12],
+        ]
+    );
+    assert_eq!(
+        accumulator.root(SCHEME),
+        bag_peaks(SCHEME, 13, &accumulator.peaks)
+    );
+}
+
+#[test]
+fn root_does_not_depend_on_epoch_boundaries() {
+    let leaves = sample_leaves(20);
+    let mut at_once = Accumulator::default();
+    at_once.extend(SCHEME, &leaves);
+
+    let mut by_epoch = Accumulator::default();
+    for epoch in leaves.chunks(3) {
+        by_epoch.extend(SCHEME, epoch);
+    }
+    assert_eq!(at_once, by_epoch);
+}
+
+#[test]
+fn historical_leaves_verify_against_latest_root() {
+    let leaves = sample_leaves(21);
+    let mut accumulator = Accumulator::default();
+    let mut appended = Vec::new();
+    for epoch in leaves.chunks(4) {
+        accumulator.extend(SCHEME, epoch);
+        appended.extend_from_slice(epoch);
+
+        let root = accumulator.root(SCHEME);
+        for (index, leaf) in appended.iter().enumerate() {
+            let proof = accumulator_proof(SCHEME, &appended, index as u64).unwrap();
+            assert_eq!(proof.peaks, accumulator.peaks);
+            assert!(verify_accumulator_inclusion(SCHEME, &root, leaf, &proof));
+        }
+    }
+}
+
+#[test]
+fn tampered_proofs_are_rejected() {
+    let leaves = sample_leaves(11);
+    let mut accumulator = Accumulator::default();
+    accumulator.extend(SCHEME, &leaves);
+    let root = accumulator.root(SCHEME);
+
+    let proof = accumulator_proof(SCHEME, &leaves, 5).unwrap();
+    assert!(!verify_accumulator_inclusion(
+        SCHEME, &root, &leaves[6], &proof
+    ));
+    assert!(!verify_accumulator_inclusion(
+        Scheme::new(LeafVersion::V0, TreeHash::Sha256),
+        &root,
+        &leaves[5],
+        &proof
+    ));
+
+    let mut wrong_index = proof.clone();
+    wrong_index.leaf_index = 4;
+    assert!(!verify_accumulator_inclusion(
+        SCHEME,
+        &root,
+        &leaves[5],
+        &wrong_index
+    ));
+
+    let mut wrong_count = proof.clone();
+    wrong_count.leaf_count = 12;
+    assert!(!verify_accumulator_inclusion(
+        SCHEME,
+        &root,
+        &leaves[5],
+        &wrong_count
+    ));
+
+    let mut missing_peak = proof;
+    missing_peak.peaks.pop();
+    assert!(!verify_accumulator_inclusion(
+        SCHEME,
+        &root,
+        &leaves[5],
+        &missing_peak
+    ));
+
+    assert!(accumulator_proof(SCHEME, &leaves, 11).is_none());
+}
@@ -0,0 +1,484 @@
+//! Checks that every consumer of the commitment agrees: with `LeafVersion::V0` the
+//! roots must match `rs_merkle` (which the guest used originally) and the Solidity
+//! test fixtures. `LeafVersion::V1` must keep leaves and nodes apart, and the keccak
+//! tree must verify the way OpenZeppelin's `MerkleProof` does. Padded trees must
+//! have the fixed depth they commit, whatever the number of leaves.
+
+use rs_merkle::{algorithms::Sha256 as MerkleSha256, Hasher, MerkleTree};
+use sha2::{Digest, Sha256};
+use shared_merkle::{
+    commit_proof_pairs, digest_pair_leaves, first_duplicate, inclusion_proof, merkle_root,
+    padded_inclusion_proof, padded_root, proof_pair_leaves, public_values_digest,
+    tree_inclusion_proof, tree_root, verify_inclusion, words_to_bytes_le, Hash, LeafVersion,
+    Scheme, Submitter, TreeHash, EMPTY_LEAF, MAX_PADDED_DEPTH,
+};
+use tiny_keccak::{Hasher as _, Keccak};
+
+const V0: Scheme = Scheme::new(LeafVersion::V0, TreeHash::Sha256);
+const V1: Scheme = Scheme::new(LeafVersion::V1, TreeHash::Sha256);
+const V2: Scheme = Scheme::new(LeafVersion::V2, TreeHash::Sha256);
+const KECCAK: Scheme = Scheme::new(LeafVersion::V1, TreeHash::Keccak);
+
+fn decode_hex(value: &str) -> Vec<u8> {
+    let value = value.trim_start_matches("0x");
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn read_fixture(name: &str) -> serde_json::Value {
+    let path = format!(
+        "{}/../contracts/src/fixtures/{}",
+        env!("CARGO_MANIFEST_DIR"),
+        name
+    );
+    serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+}
+
+fn sample_leaves(scheme: Scheme, count: usize) -> Vec<Hash> {
+    (0..count)
+        .map(|i| {
+            scheme.leaf_hash(
+                &[i as u32; 8],
+                &(i as u64).to_le_bytes(),
+                &Submitter::default(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn words_are_little_endian() {
+    let bytes = words_to_bytes_le(&[0x04030201, 0, 0, 0, 0, 0, 0, 0x08070605]);
+    assert_eq!(bytes[..4], [1, 2, 3, 4]);
+    assert_eq!(bytes[28..], [5, 6, 7, 8]);
+}
+
+#[test]
+fn root_matches_solidity_fixture() {
+    let offchain = read_fixture("groth16-offchain.json");
+    let onchain = read_fixture("groth16-onchain.json");
+
+    let leaves: Vec<Hash> = offchain["verificationKeys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .zip(offchain["publicValues"].as_array().unwrap())
+        .map(|(vkey, public_values)| {
+            let vkey: [u8; 32] = decode_hex(vkey.as_str().unwrap()).try_into().unwrap();
+            V0.leaf_hash_from_bytes(
+                &vkey,
+                &decode_hex(public_values.as_str().unwrap()),
+                &Submitter::default(),
+            )
+        })
+        .collect();
+
+    let expected = decode_hex(onchain["merkle_root"].as_str().unwrap());
+    assert_eq!(merkle_root(V0, &leaves).unwrap().to_vec(), expected);
+}
+
+#[test]
+fn root_matches_rs_merkle() {
+    for count in 1..=33 {
+        let leaves = sample_leaves(V0, count);
+        let expected = MerkleTree::<MerkleSha256>::from_leaves(&leaves).root();
+        assert_eq!(merkle_root(V0, &leaves), expected, "{} leaves", count);
+    }
+}
+
+#[test]
+fn leaf_matches_rs_merkle() {
+    let vkey = [7u32; 8];
+    let public_values = b"public values".to_vec();
+    let concat = [&words_to_bytes_le(&vkey)[..], &public_values].concat();
+    assert_eq!(
+        V0.leaf_hash(&vkey, &public_values, &Submitter::default()),
+        MerkleSha256::hash(&concat)
+    );
+    assert_eq!(
+        commit_proof_pairs(V0, &[vkey], &[public_values.clone()], &[], &[]),
+        Some(MerkleSha256::hash(&concat))
+    );
+}
+
+#[test]
+fn commit_rejects_empty_and_mismatched_input() {
+    for scheme in [V0, V1, V2, KECCAK] {
+        assert_eq!(commit_proof_pairs(scheme, &[], &[], &[], &[]), None);
+        assert_eq!(commit_proof_pairs(scheme, &[[0; 8]], &[], &[], &[]), None);
+    }
+}
+
+#[test]
+fn inclusion_proofs_verify_for_every_leaf() {
+    for count in 1..=17 {
+        let leaves = sample_leaves(V0, count);
+        let root = merkle_root(V0, &leaves).unwrap();
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = inclusion_proof(V0, &leaves, index).unwrap();
+            assert!(
+                verify_inclusion(V0, &root, leaf, &proof),
+                "{count} leaves, index {index}"
+            );
+
+            let rs_proof = MerkleTree::<MerkleSha256>::from_leaves(&leaves).proof(&[index]);
+            assert_eq!(proof.siblings, rs_proof.proof_hashes());
+        }
+    }
+}
+
+#[test]
+fn inclusion_proofs_reject_wrong_leaf_or_position() {
+    for scheme in [V0, V1, V2, KECCAK] {
+        let leaves = sample_leaves(scheme, 5);
+        let root = merkle_root(scheme, &leaves).unwrap();
+        let proof = inclusion_proof(scheme, &leaves, 2).unwrap();
+
+        assert!(verify_inclusion(scheme, &root, &leaves[2], &proof));
+        assert!(!verify_inclusion(scheme, &root, &leaves[3], &proof));
+
+        // Sorted pairs do not bind the position, only membership
+        if scheme.tree_hash == TreeHash::Sha256 {
+            let mut moved = proof.clone();
+            moved.leaf_index = 3;
+            assert!(!verify_inclusion(scheme, &root, &leaves[2], &moved));
+        }
+
+        assert!(inclusion_proof(scheme, &leaves, 5).is_none());
+    }
+}
+
+#[test]
+fn v0_leaf_can_pass_for_a_node_but_v1_cannot() {
+    let left = [1u8; 32];
+    let right = [2u8; 32];
+
+    // A "leaf" whose vkey and public values are two child hashes
+    assert_eq!(
+        V0.leaf_hash_from_bytes(&left, &right, &Submitter::default()),
+        V0.node_hash(&left, &right)
+    );
+    assert_ne!(
+        V1.leaf_hash_from_bytes(&left, &right, &Submitter::default()),
+        V1.node_hash(&left, &right)
+    );
+}
+
+#[test]
+fn v1_leaf_is_tagged_and_length_prefixed() {
+    let vkey = [7u32; 8];
+    let public_values = b"public values".to_vec();
+    let mut preimage = vec![0x00];
+    preimage.extend_from_slice(&words_to_bytes_le(&vkey));
+    preimage.extend_from_slice(&(public_values.len() as u64).to_be_bytes());
+    preimage.extend_from_slice(&public_values);
+    assert_eq!(
+        V1.leaf_hash(&vkey, &public_values, &Submitter::default()),
+        <[u8; 32]>::from(Sha256::digest(&preimage))
+    );
+
+    let left = [1u8; 32];
+    let right = [2u8; 32];
+    let node_preimage = [&[0x01][..], &left, &right].concat();
+    assert_eq!(
+        V1.node_hash(&left, &right),
+        <[u8; 32]>::from(Sha256::digest(&node_preimage))
+    );
+}
+
+#[test]
+fn v2_leaf_commits_the_submitter() {
+    let vkey = [7u32; 8];
+    let public_values = b"public values".to_vec();
+    let submitter = V2.submitter("node.os", 3);
+    assert_eq!(
+        submitter.id_hash,
+        <[u8; 32]>::from(Sha256::digest(b"node.os"))
+    );
+
+    let mut preimage = vec![0x00];
+    preimage.extend_from_slice(&words_to_bytes_le(&vkey));
+    preimage.extend_from_slice(&(public_values.len() as u64).to_be_bytes());
+    preimage.extend_from_slice(&public_values);
+    preimage.extend_from_slice(&submitter.id_hash);
+    preimage.extend_from_slice(&3u64.to_be_bytes());
+    assert_eq!(
+        V2.leaf_hash(&vkey, &public_values, &submitter),
+        <[u8; 32]>::from(Sha256::digest(&preimage))
+    );
+
+    // Same proof from another node, or again from the same node, is another leaf
+    let leaf = V2.leaf_hash(&vkey, &public_values, &submitter);
+    let other_node = V2.submitter("other.os", 3);
+    let next_nonce = V2.submitter("node.os", 4);
+    assert_ne!(leaf, V2.leaf_hash(&vkey, &public_values, &other_node));
+    assert_ne!(leaf, V2.leaf_hash(&vkey, &public_values, &next_nonce));
+
+    // Earlier versions ignore the submitter
+    assert_eq!(
+        V1.leaf_hash(&vkey, &public_values, &submitter),
+        V1.leaf_hash(&vkey, &public_values, &other_node)
+    );
+}
+
+#[test]
+fn v2_leaves_need_a_submitter_per_proof() {
+    let vkeys = [[1u32; 8], [2u32; 8]];
+    let values = vec![b"a".to_vec(), b"b".to_vec()];
+    let submitters = [V2.submitter("a.os", 0), V2.submitter("b.os", 0)];
+
+    assert!(proof_pair_leaves(V2, &vkeys, &values, &[], &[]).is_none());
+    assert!(proof_pair_leaves(V2, &vkeys, &values, &submitters[..1], &[]).is_none());
+    assert_eq!(
+        proof_pair_leaves(V2, &vkeys, &values, &submitters, &[]).unwrap(),
+        vec![
+            V2.leaf_hash(&vkeys[0], &values[0], &submitters[0]),
+            V2.leaf_hash(&vkeys[1], &values[1], &submitters[1]),
+        ]
+    );
+    assert!(proof_pair_leaves(V1, &vkeys, &values, &[], &[]).is_some());
+}
+
+#[test]
+fn hashed_leaves_commit_the_salted_digest() {
+    let vkey = [7u32; 8];
+    let public_values = b"a large public output".to_vec();
+    let salt = [9u8; 32];
+    let hashed = V1.with_hashed_public_values(true);
+
+    let digest = <[u8; 32]>::from(Sha256::digest(&public_values));
+    assert_eq!(public_values_digest(&public_values, None), digest);
+    let salted = <[u8; 32]>::from(Sha256::digest([&salt[..], &digest].concat()));
+    assert_eq!(public_values_digest(&public_values, Some(&salt)), salted);
+
+    // Anyone with the digest can recompute the leaf without the raw values
+    let leaves = proof_pair_leaves(
+        hashed,
+        &[vkey, vkey],
+        &[public_values.clone(), public_values.clone()],
+        &[],
+        &[None, Some(salt)],
+    )
+    .unwrap();
+    let anyone = Submitter::default();
+    assert_eq!(leaves[0], hashed.leaf_hash(&vkey, &digest, &anyone));
+    assert_eq!(leaves[1], hashed.leaf_hash(&vkey, &salted, &anyone));
+    assert_ne!(leaves[0], V1.leaf_hash(&vkey, &public_values, &anyone));
+
+    // The program builds the same leaves from the digests it verifies the proofs with
+    assert_eq!(
+        digest_pair_leaves(
+            hashed,
+            &[vkey, vkey],
+            &[digest, digest],
+            &[],
+            &[None, Some(salt)]
+        ),
+        Some(leaves)
+    );
+    assert!(digest_pair_leaves(V1, &[vkey], &[digest], &[], &[]).is_none());
+
+    // Salts are ignored when the raw values are committed, but must line up with the proofs
+    let raw = [public_values.clone()];
+    assert_eq!(
+        proof_pair_leaves(V1, &[vkey], &raw, &[], &[Some(salt)]),
+        proof_pair_leaves(V1, &[vkey], &raw, &[], &[])
+    );
+    assert!(proof_pair_leaves(hashed, &[vkey], &[public_values], &[], &[None, None]).is_none());
+}
+
+#[test]
+fn scheme_parts_parse_and_round_trip_through_u8() {
+    assert_eq!("v1".parse::<LeafVersion>(), Ok(LeafVersion::V1));
+    assert_eq!("V0".parse::<LeafVersion>(), Ok(LeafVersion::V0));
+    assert_eq!("v2".parse::<LeafVersion>(), Ok(LeafVersion::V2));
+    assert!("v3".parse::<LeafVersion>().is_err());
+    for version in [LeafVersion::V0, LeafVersion::V1, LeafVersion::V2] {
+        assert_eq!(LeafVersion::try_from(version as u8), Ok(version));
+    }
+    assert!(LeafVersion::try_from(3).is_err());
+
+    assert_eq!("keccak".parse::<TreeHash>(), Ok(TreeHash::Keccak));
+    assert!("blake3".parse::<TreeHash>().is_err());
+    for tree_hash in [TreeHash::Sha256, TreeHash::Keccak] {
+        assert_eq!(TreeHash::try_from(tree_hash as u8), Ok(tree_hash));
+    }
+}
+
+fn keccak(data: &[u8]) -> Hash {
+    let mut hasher = Keccak::v256();
+    hasher.update(data);
+    let mut digest = [0u8; 32];
+    hasher.finalize(&mut digest);
+    digest
+}
+
+// OpenZeppelin's `MerkleProof.processProof`
+fn process_proof(proof: &[Hash], leaf: Hash) -> Hash {
+    proof.iter().fold(leaf, |computed, sibling| {
+        if computed <= *sibling {
+            keccak(&[computed, *sibling].concat())
+        } else {
+            keccak(&[*sibling, computed].concat())
+        }
+    })
+}
+
+#[test]
+fn keccak_leaf_is_double_hashed() {
+    let vkey = [7u32; 8];
+    let public_values = b"public values".to_vec();
+    let mut preimage = vec![0x00];
+    preimage.extend_from_slice(&words_to_bytes_le(&vkey));
+    preimage.extend_from_slice(&(public_values.len() as u64).to_be_bytes());
+    preimage.extend_from_slice(&public_values);
+    assert_eq!(
+        KECCAK.leaf_hash(&vkey, &public_values, &Submitter::default()),
+        keccak(&keccak(&preimage))
+    );
+}
+
+// Same vectors as `test_LeafHashMatchesScheme` in the contract tests
+#[test]
+fn keccak_leaves_match_the_contract() {
+    let vkey = [0x11u8; 32];
+    let submitter = Submitter {
+        id_hash: [0x22; 32],
+        nonce: 7,
+    };
+    let expected = [
+        (
+            LeafVersion::V0,
+            "f5103f65d798c61025745a640fba41ac2f91745c5af3442fb73063c127c8d1b9",
+        ),
+        (
+            LeafVersion::V1,
+            "be6bd5cea9d2ef820b7b7f059a52ba95a357808a564be8ad7857f1c7c416e30f",
+        ),
+        (
+            LeafVersion::V2,
+            "17ef79fe9c45ef2a781736ddabc87aa9592cafe811528901c994e009e0f57756",
+        ),
+    ];
+    for (version, leaf) in expected {
+        let scheme = Scheme::new(version, TreeHash::Keccak);
+        assert_eq!(
+            scheme
+                .leaf_hash_from_bytes(&vkey, b"a", &submitter)
+                .to_vec(),
+            decode_hex(leaf),
+            "{version:?}"
+        );
+    }
+}
+
+#[test]
+fn keccak_siblings_verify_like_openzeppelin() {
+    for count in 1..=17 {
+        let leaves = sample_leaves(KECCAK, count);
+        let root = merkle_root(KECCAK, &leaves).unwrap();
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = inclusion_proof(KECCAK, &leaves, index).unwrap();
+            assert_eq!(
+                process_proof(&proof.siblings, *leaf),
+                root,
+                "{count} leaves, index {index}"
+            );
+        }
+    }
+}
+
+#[test]
+fn padded_root_fills_the_tree_with_empty_leaves() {
+    for scheme in [V0, V1, KECCAK] {
+        let leaves = sample_leaves(scheme, 5);
+        let mut full = leaves.clone();
+        full.resize(8, EMPTY_LEAF);
+        assert_eq!(padded_root(scheme, &leaves, 3), merkle_root(scheme, &full));
+
+        let mut deeper = leaves.clone();
+        deeper.resize(16, EMPTY_LEAF);
+        assert_eq!(
+            padded_root(scheme, &leaves, 4),
+            merkle_root(scheme, &deeper)
+        );
+    }
+}
+
+#[test]
+fn padded_root_of_no_leaves_is_the_empty_tree() {
+    let mut empty = EMPTY_LEAF;
+    for depth in 1..=MAX_PADDED_DEPTH {
+        empty = V1.node_hash(&empty, &empty);
+        assert_eq!(padded_root(V1, &[], depth), Some(empty), "depth {depth}");
+    }
+
+    let padded = V1.with_padded_depth(Some(4));
+    assert_eq!(
+        commit_proof_pairs(padded, &[], &[], &[], &[]),
+        padded_root(V1, &[], 4)
+    );
+}
+
+#[test]
+fn padded_root_rejects_leaves_that_do_not_fit() {
+    let leaves = sample_leaves(V1, 9);
+    assert!(padded_root(V1, &leaves, 3).is_none());
+    assert!(padded_root(V1, &leaves[..8], 3).is_some());
+    assert!(padded_root(V1, &leaves[..1], 0).is_none());
+    assert!(padded_root(V1, &leaves[..1], MAX_PADDED_DEPTH + 1).is_none());
+}
+
+#[test]
+fn deepest_padded_tree_has_proofs() {
+    let leaves = sample_leaves(V1, 2);
+    let root = padded_root(V1, &leaves, MAX_PADDED_DEPTH).unwrap();
+    let proof = padded_inclusion_proof(V1, &leaves, 1, MAX_PADDED_DEPTH).unwrap();
+    assert_eq!(proof.leaf_count, 1 << MAX_PADDED_DEPTH);
+    assert_eq!(proof.siblings.len(), MAX_PADDED_DEPTH as usize);
+    assert!(verify_inclusion(V1, &root, &leaves[1], &proof));
+}
+
+#[test]
+fn padded_proofs_have_one_sibling_per_level() {
+    let padded = V1.with_padded_depth(Some(5));
+    for count in 1..=17 {
+        let leaves = sample_leaves(padded, count);
+        let root = tree_root(padded, &leaves).unwrap();
+        assert_eq!(Some(root), padded_root(V1, &leaves, 5));
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = tree_inclusion_proof(padded, &leaves, index).unwrap();
+            assert_eq!(proof.siblings.len(), 5);
+            assert_eq!(
+                proof,
+                padded_inclusion_proof(V1, &leaves, index, 5).unwrap()
+            );
+            assert!(
+                verify_inclusion(padded, &root, leaf, &proof),
+                "{count} leaves, index {index}"
+            );
+            assert!(!verify_inclusion(padded, &root, &EMPTY_LEAF, &proof));
+        }
+        assert!(tree_inclusion_proof(padded, &leaves, count).is_none());
+    }
+}
+
+#[test]
+fn identical_pairs_are_duplicate_leaves_unless_the_submitter_differs() {
+    let vkeys = [[1u32; 8], [2u32; 8], [1u32; 8]];
+    let values = vec![vec![7u8; 40], vec![7u8; 40], vec![7u8; 40]];
+    let leaves = proof_pair_leaves(V1, &vkeys, &values, &[], &[]).unwrap();
+    assert_eq!(first_duplicate(&leaves), Some((0, 2)));
+    assert_eq!(first_duplicate(&leaves[..2]), None);
+    assert_eq!(first_duplicate(&[]), None);
+
+    let submitters = [
+        V2.submitter("a.os", 0),
+        V2.submitter("b.os", 0),
+        V2.submitter("c.os", 0),
+    ];
+    let bound = proof_pair_leaves(V2, &vkeys, &values, &submitters, &[]).unwrap();
+    assert_eq!(first_duplicate(&bound), None);
+}
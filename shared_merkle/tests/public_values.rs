@@ -0,0 +1,59 @@
+use alloy_sol_types::SolType;
+use shared_merkle::public_values::{chain_digest, AggregationPublicValues};
+
+#[test]
+fn public_values_round_trip_as_a_static_tuple() {
+    let values = AggregationPublicValues {
+        epoch: 7,
+        leafCount: 3,
+        prevRoot: [1u8; 32].into(),
+        newRoot: [2u8; 32].into(),
+        prevAccumulatorRoot: [3u8; 32].into(),
+        accumulatorRoot: [4u8; 32].into(),
+        accumulatorLeafCount: 12,
+        selfVkey: [5u8; 32].into(),
+        chainDigest: [6u8; 32].into(),
+        chainStartEpoch: 2,
+        leafVersion: 1,
+        treeHash: 1,
+        hashedPublicValues: true,
+        treeDepth: 20,
+    };
+    let encoded = AggregationPublicValues::abi_encode(&values);
+
+    // Fourteen 32 byte words, the layout `abi.decode(_publicValues, (AggregationPublicValues))` expects
+    assert_eq!(encoded.len(), 448);
+    assert_eq!(encoded[31], 7);
+    assert_eq!(encoded[63], 3);
+    assert_eq!(encoded[223], 12);
+
+    let decoded = AggregationPublicValues::abi_decode(&encoded, true).unwrap();
+    assert_eq!(decoded.epoch, 7);
+    assert_eq!(decoded.leafCount, 3);
+    assert_eq!(decoded.prevRoot, values.prevRoot);
+    assert_eq!(decoded.newRoot, values.newRoot);
+    assert_eq!(decoded.prevAccumulatorRoot, values.prevAccumulatorRoot);
+    assert_eq!(decoded.accumulatorRoot, values.accumulatorRoot);
+    assert_eq!(decoded.accumulatorLeafCount, 12);
+    assert_eq!(decoded.selfVkey, values.selfVkey);
+    assert_eq!(decoded.chainDigest, values.chainDigest);
+    assert_eq!(decoded.chainStartEpoch, 2);
+    assert_eq!(decoded.leafVersion, 1);
+    assert_eq!(decoded.treeHash, 1);
+    assert!(decoded.hashedPublicValues);
+    assert_eq!(decoded.treeDepth, 20);
+}
+
+#[test]
+fn chain_digest_commits_to_its_predecessor() {
+    let root = [1u8; 32];
+    let accumulator_root = [2u8; 32];
+    let first = chain_digest(&[0u8; 32], 1, &root, &accumulator_root);
+    let second = chain_digest(&first, 2, &root, &accumulator_root);
+
+    let unchained = chain_digest(&[0u8; 32], 2, &root, &accumulator_root);
+
+    assert_ne!(first, second);
+    assert_ne!(second, unchained);
+    assert_ne!(first, unchained);
+}
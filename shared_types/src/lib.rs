@@ -1,15 +1,140 @@
 use serde::{Deserialize, Serialize};
-use sp1_sdk::{SP1ProofWithPublicValues, SP1VerifyingKey};
+use shared_merkle::{accumulator::Accumulator, Scheme, Submitter};
+use sp1_sdk::{SP1Proof, SP1ProofWithPublicValues, SP1VerifyingKey};
+use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AggregationInput {
     pub proof: SP1ProofWithPublicValues,
     pub vk: SP1VerifyingKey,
+    /// Set by the process when the epoch is frozen, committed by leaf versions that bind it.
+    #[serde(default)]
+    pub submitter: Option<Submitter>,
+    /// Blinds the digest of the public values when leaves commit it instead of the values.
+    #[serde(default)]
+    pub salt: Option<[u8; 32]>,
+}
+
+/// Kind of aggregate proof a job asks for. Groth16 and Plonk can be checked
+/// on chain, Compressed is meant for recursion or off-chain use.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AggregationProofMode {
+    #[default]
+    Groth16,
+    Plonk,
+    Compressed,
+}
+
+impl AggregationProofMode {
+    /// Mode of an existing proof, `None` for core proofs.
+    pub fn of(proof: &SP1Proof) -> Option<Self> {
+        match proof {
+            SP1Proof::Groth16(_) => Some(Self::Groth16),
+            SP1Proof::Plonk(_) => Some(Self::Plonk),
+            SP1Proof::Compressed(_) => Some(Self::Compressed),
+            _ => None,
+        }
+    }
+
+    pub fn is_onchain_verifiable(&self) -> bool {
+        matches!(self, Self::Groth16 | Self::Plonk)
+    }
+}
+
+impl FromStr for AggregationProofMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "groth16" => Ok(Self::Groth16),
+            "plonk" => Ok(Self::Plonk),
+            "compressed" => Ok(Self::Compressed),
+            other => Err(format!(
+                "Unknown proof mode {}, expected groth16, plonk or compressed",
+                other
+            )),
+        }
+    }
+}
+
+/// A batch of proofs sent from the process to the extension for aggregation.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregationJob {
+    pub epoch: u64,
+    pub inputs: Vec<AggregationInput>,
+    #[serde(default)]
+    pub proof_mode: AggregationProofMode,
+    /// Root of the previous aggregate, committed so the chain can check ordering.
+    #[serde(default)]
+    pub prev_root: [u8; 32],
+    /// Accumulator over every earlier epoch, which this epoch's leaves are appended to.
+    #[serde(default)]
+    pub accumulator: Accumulator,
+    /// How leaves and nodes are hashed, a job without it uses the unversioned sha256 format.
+    #[serde(flatten)]
+    pub scheme: Scheme,
+    /// Also produce a compressed aggregate that the next epoch can verify in the program.
+    #[serde(default)]
+    pub chain: bool,
+    /// Compressed aggregate of the previous epoch, verified in the program to extend its chain.
+    #[serde(default)]
+    pub prev_aggregate: Option<SP1ProofWithPublicValues>,
+    /// Only execute the program on the batch and report its cycles, without proving.
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AggregationOutput {
+    pub epoch: u64,
     pub proof: SP1ProofWithPublicValues,
+    /// Inputs that failed verification and were left out of the aggregate.
+    #[serde(default)]
+    pub rejected: Vec<RejectedInput>,
+    /// Compressed aggregate of the same epoch when the job asked to `chain`.
+    #[serde(default)]
+    pub compressed: Option<SP1ProofWithPublicValues>,
+    /// Cycles the program took to execute the batch before it was proven.
+    #[serde(default)]
+    pub cycles: Option<u64>,
+}
+
+/// Sent back by the extension for a `dry_run` job: the batch executed and would be proven.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DryRunReport {
+    pub epoch: u64,
+    pub cycles: u64,
+    pub leaf_count: usize,
+    #[serde(default)]
+    pub rejected: Vec<RejectedInput>,
+}
+
+/// An input the extension excluded from aggregation, by its position in `AggregationJob::inputs`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RejectedInput {
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Sent back by the extension when a job could not be aggregated.
+/// `epoch` is `None` when the job itself could not be decoded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AggregationFailure {
+    pub epoch: Option<u64>,
+    pub reason: String,
+    #[serde(default)]
+    pub rejected: Vec<RejectedInput>,
+}
+
+/// Sent by the extension when it connects. `program_vkey` is the vk of the aggregator
+/// program it embeds, as the `bytes32` the verifier contract keeps in `PROGRAM_VKEY`, and
+/// `self_vkey` the same vk as the program commits it, which the contract keeps in `SELF_VKEY`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorkerHandshake {
+    pub program_vkey: String,
+    #[serde(default)]
+    pub self_vkey: String,
+    pub backend: String,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -21,6 +146,9 @@ pub struct DummyProofInsert {
 #[serde(untagged)]
 pub enum WsMessage {
     Aggregation(AggregationOutput),
+    AggregationFailure(AggregationFailure),
+    DryRun(DryRunReport),
+    Handshake(WorkerHandshake),
     DummyProof(DummyProofInsert),
 }
 
@@ -28,6 +156,8 @@ impl std::fmt::Debug for AggregationInput {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AggregationInput")
             .field("proof", &self.proof)
+            .field("submitter", &self.submitter)
+            .field("salted", &self.salt.is_some())
             .finish()
     }
 }
@@ -0,0 +1,270 @@
+use crate::config::BackendKind;
+use async_trait::async_trait;
+use hashbrown::HashMap;
+use p3_baby_bear::BabyBear;
+use p3_field::AbstractField;
+use p3_fri::{FriProof, TwoAdicFriPcsProof};
+use shared_types::AggregationInput;
+use sp1_prover::{SP1CoreProof, SP1CoreProofData, SP1ReduceProof};
+use sp1_sdk::network::proto::network::ProofMode;
+use sp1_sdk::{
+    NetworkProverV1, Prover, ProverClient, SP1Context, SP1Proof, SP1ProofKind,
+    SP1ProofWithPublicValues, SP1Prover, SP1PublicValues, SP1Stdin, SP1VerifyingKey,
+};
+use sp1_stark::{SP1ProverOpts, ShardCommitment, ShardOpenedValues, ShardProof, StarkVerifyingKey};
+use std::sync::Arc;
+
+/// Somewhere to generate proofs of a program. Picked with `PROVER_BACKEND`.
+#[async_trait]
+pub trait ProverBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn prove(
+        &self,
+        elf: &'static [u8],
+        stdin: SP1Stdin,
+        kind: SP1ProofKind,
+    ) -> anyhow::Result<SP1ProofWithPublicValues>;
+
+    /// Checks a proof against its verifying key on this machine.
+    fn verify(&self, proof: &SP1ProofWithPublicValues, vk: &SP1VerifyingKey) -> anyhow::Result<()>;
+
+    /// Verifying key of a program, used to check the proofs this backend returns.
+    fn verifying_key(&self, elf: &[u8]) -> SP1VerifyingKey;
+
+    /// Compresses a core (sharded) input proof so it can be verified in the aggregator program.
+    fn compress(&self, input: &AggregationInput) -> anyhow::Result<SP1ProofWithPublicValues>;
+
+    /// Executes a program without proving it, on this machine. Returns the public values it
+    /// commits and the number of cycles it took.
+    fn execute(&self, elf: &[u8], stdin: &SP1Stdin) -> anyhow::Result<(SP1PublicValues, u64)>;
+}
+
+pub fn build_backend(kind: BackendKind) -> Arc<dyn ProverBackend> {
+    match kind {
+        BackendKind::Network => Arc::new(NetworkBackend::new()),
+        BackendKind::Cpu => Arc::new(LocalBackend::cpu()),
+        BackendKind::Mock => Arc::new(LocalBackend::mock()),
+    }
+}
+
+/// Proves on the Succinct prover network. Requires `SP1_PRIVATE_KEY`.
+pub struct NetworkBackend {
+    prover: NetworkProverV1,
+}
+
+impl NetworkBackend {
+    pub fn new() -> Self {
+        Self {
+            prover: NetworkProverV1::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ProverBackend for NetworkBackend {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    async fn prove(
+        &self,
+        elf: &'static [u8],
+        stdin: SP1Stdin,
+        kind: SP1ProofKind,
+    ) -> anyhow::Result<SP1ProofWithPublicValues> {
+        let mode = match kind {
+            SP1ProofKind::Core => ProofMode::Core,
+            SP1ProofKind::Compressed => ProofMode::Compressed,
+            SP1ProofKind::Plonk => ProofMode::Plonk,
+            SP1ProofKind::Groth16 => ProofMode::Groth16,
+        };
+        self.prover
+            .prove(elf, stdin, mode, None)
+            .await
+            .map_err(|e| anyhow::anyhow!("Proving failed: {}", e))
+    }
+
+    fn verify(&self, proof: &SP1ProofWithPublicValues, vk: &SP1VerifyingKey) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.prover.verify(proof, vk))
+            .map_err(|e| anyhow::anyhow!("Verification failed: {}", e))
+    }
+
+    fn verifying_key(&self, elf: &[u8]) -> SP1VerifyingKey {
+        let (_pk, vk) = self.prover.setup(elf);
+        vk
+    }
+
+    // The network only proves programs, so compression happens locally
+    fn compress(&self, input: &AggregationInput) -> anyhow::Result<SP1ProofWithPublicValues> {
+        compress_locally(self.prover.sp1_prover(), input)
+    }
+
+    // Execution is free and needs no network, so it is local too
+    fn execute(&self, elf: &[u8], stdin: &SP1Stdin) -> anyhow::Result<(SP1PublicValues, u64)> {
+        execute_locally(self.prover.sp1_prover(), elf, stdin)
+    }
+}
+
+/// Proves on this machine. With the CPU prover it works offline, but Groth16 wrapping is
+/// slow and needs Docker. The mock prover executes the program for real and returns a mock
+/// proof of the requested kind: the public values match what a real proof would commit, but
+/// the proof bytes are empty so only `SP1MockVerifier` accepts them on chain. Deterministic
+/// and offline, meant for development and CI. Input proofs are compressed into mock proofs
+/// the same way.
+pub struct LocalBackend {
+    client: Arc<ProverClient>,
+    name: &'static str,
+    mock: bool,
+}
+
+impl LocalBackend {
+    pub fn cpu() -> Self {
+        Self {
+            client: Arc::new(ProverClient::local()),
+            name: "cpu",
+            mock: false,
+        }
+    }
+
+    pub fn mock() -> Self {
+        Self {
+            client: Arc::new(ProverClient::mock()),
+            name: "mock",
+            mock: true,
+        }
+    }
+}
+
+#[async_trait]
+impl ProverBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Local proving is blocking, so keep it off the async runtime
+    async fn prove(
+        &self,
+        elf: &'static [u8],
+        stdin: SP1Stdin,
+        kind: SP1ProofKind,
+    ) -> anyhow::Result<SP1ProofWithPublicValues> {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || {
+            let (pk, _vk) = client.setup(elf);
+            let prove = client.prove(&pk, stdin);
+            let prove = match kind {
+                SP1ProofKind::Core => prove.core(),
+                SP1ProofKind::Compressed => prove.compressed(),
+                SP1ProofKind::Plonk => prove.plonk(),
+                SP1ProofKind::Groth16 => prove.groth16(),
+            };
+            prove.run()
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!("Proving task failed: {}", e))?
+        .map_err(|e| anyhow::anyhow!("Proving failed: {}", e))
+    }
+
+    fn verify(&self, proof: &SP1ProofWithPublicValues, vk: &SP1VerifyingKey) -> anyhow::Result<()> {
+        tokio::task::block_in_place(|| self.client.verify(proof, vk))
+            .map_err(|e| anyhow::anyhow!("Verification failed: {}", e))
+    }
+
+    fn verifying_key(&self, elf: &[u8]) -> SP1VerifyingKey {
+        let (_pk, vk) = self.client.setup(elf);
+        vk
+    }
+
+    fn compress(&self, input: &AggregationInput) -> anyhow::Result<SP1ProofWithPublicValues> {
+        if self.mock {
+            mock_compress(input)
+        } else {
+            compress_locally(self.client.prover.sp1_prover(), input)
+        }
+    }
+
+    fn execute(&self, elf: &[u8], stdin: &SP1Stdin) -> anyhow::Result<(SP1PublicValues, u64)> {
+        let (public_values, report) =
+            tokio::task::block_in_place(|| self.client.execute(elf, stdin.clone()).run())
+                .map_err(|e| anyhow::anyhow!("Execution failed: {}", e))?;
+        Ok((public_values, report.total_instruction_count()))
+    }
+}
+
+fn execute_locally(
+    prover: &SP1Prover,
+    elf: &[u8],
+    stdin: &SP1Stdin,
+) -> anyhow::Result<(SP1PublicValues, u64)> {
+    let (public_values, report) =
+        tokio::task::block_in_place(|| prover.execute(elf, stdin, SP1Context::default()))
+            .map_err(|e| anyhow::anyhow!("Execution failed: {}", e))?;
+    Ok((public_values, report.total_instruction_count()))
+}
+
+fn compress_locally(
+    prover: &SP1Prover,
+    input: &AggregationInput,
+) -> anyhow::Result<SP1ProofWithPublicValues> {
+    let SP1Proof::Core(shard_proofs) = &input.proof.proof else {
+        return Err(anyhow::anyhow!("Only core proofs can be compressed"));
+    };
+    let core_proof = SP1CoreProof {
+        proof: SP1CoreProofData(shard_proofs.clone()),
+        stdin: input.proof.stdin.clone(),
+        public_values: input.proof.public_values.clone(),
+        cycles: 0,
+    };
+    let compressed = tokio::task::block_in_place(|| {
+        prover.compress(&input.vk, core_proof, vec![], SP1ProverOpts::default())
+    })
+    .map_err(|e| anyhow::anyhow!("Compression failed: {}", e))?;
+
+    Ok(SP1ProofWithPublicValues {
+        proof: SP1Proof::Compressed(Box::new(compressed)),
+        stdin: input.proof.stdin.clone(),
+        public_values: input.proof.public_values.clone(),
+        sp1_version: input.proof.sp1_version.clone(),
+    })
+}
+
+// Compressed proof with empty commitments and openings, as the mock prover returns
+fn mock_compress(input: &AggregationInput) -> anyhow::Result<SP1ProofWithPublicValues> {
+    if !matches!(input.proof.proof, SP1Proof::Core(_)) {
+        return Err(anyhow::anyhow!("Only core proofs can be compressed"));
+    }
+    let proof = ShardProof {
+        commitment: ShardCommitment {
+            global_main_commit: [BabyBear::zero(); 8].into(),
+            local_main_commit: [BabyBear::zero(); 8].into(),
+            permutation_commit: [BabyBear::zero(); 8].into(),
+            quotient_commit: [BabyBear::zero(); 8].into(),
+        },
+        opened_values: ShardOpenedValues { chips: vec![] },
+        opening_proof: TwoAdicFriPcsProof {
+            fri_proof: FriProof {
+                commit_phase_commits: vec![],
+                query_proofs: vec![],
+                final_poly: Default::default(),
+                pow_witness: BabyBear::zero(),
+            },
+            query_openings: vec![],
+        },
+        chip_ordering: HashMap::new(),
+        public_values: vec![],
+    };
+    let vk = StarkVerifyingKey {
+        commit: [BabyBear::zero(); 8].into(),
+        pc_start: BabyBear::zero(),
+        chip_information: vec![],
+        chip_ordering: HashMap::new(),
+    };
+
+    Ok(SP1ProofWithPublicValues {
+        proof: SP1Proof::Compressed(Box::new(SP1ReduceProof { vk, proof })),
+        stdin: input.proof.stdin.clone(),
+        public_values: input.proof.public_values.clone(),
+        sp1_version: input.proof.sp1_version.clone(),
+    })
+}
@@ -0,0 +1,69 @@
+use std::env;
+use std::str::FromStr;
+
+const DEFAULT_MAX_CONCURRENT_JOBS: usize = 2;
+
+/// Where aggregation proofs are generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    Network,
+    Cpu,
+    Mock,
+}
+
+impl FromStr for BackendKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "network" => Ok(Self::Network),
+            "cpu" | "local" => Ok(Self::Cpu),
+            "mock" => Ok(Self::Mock),
+            other => Err(anyhow::anyhow!(
+                "Unknown PROVER_BACKEND {}, expected network, cpu or mock",
+                other
+            )),
+        }
+    }
+}
+
+/// Worker settings read from the environment (see `.env.example`).
+pub struct WorkerConfig {
+    /// Maximum number of aggregation jobs proving at the same time.
+    pub max_concurrent_jobs: usize,
+    /// Prover used for aggregation, `network` unless `PROVER_BACKEND` says otherwise.
+    pub backend: BackendKind,
+    /// Batches that take more cycles to execute are rejected before proving, no limit by default.
+    pub max_cycles: Option<u64>,
+}
+
+impl WorkerConfig {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let max_concurrent_jobs = match env::var("MAX_CONCURRENT_JOBS") {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|e| anyhow::anyhow!("Invalid MAX_CONCURRENT_JOBS {}: {}", value, e))?,
+            Err(_) => DEFAULT_MAX_CONCURRENT_JOBS,
+        };
+        if max_concurrent_jobs == 0 {
+            return Err(anyhow::anyhow!("MAX_CONCURRENT_JOBS must be at least 1"));
+        }
+        let backend = match env::var("PROVER_BACKEND") {
+            Ok(value) => value.parse()?,
+            Err(_) => BackendKind::Network,
+        };
+        let max_cycles = match env::var("MAX_CYCLES") {
+            Ok(value) if !value.is_empty() => Some(
+                value
+                    .parse::<u64>()
+                    .map_err(|e| anyhow::anyhow!("Invalid MAX_CYCLES {}: {}", value, e))?,
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            max_concurrent_jobs,
+            backend,
+            max_cycles,
+        })
+    }
+}
@@ -1,14 +1,29 @@
+use alloy_sol_types::SolType;
+use backend::{build_backend, ProverBackend};
+use config::WorkerConfig;
 use dotenv::dotenv;
 use futures_util::{SinkExt, StreamExt};
-use shared_types::{AggregationInput, AggregationOutput, DummyProofInsert};
-use sp1_sdk::network::proto::network::ProofMode;
+use shared_merkle::{
+    first_duplicate, fits_padded, proof_pair_leaves,
+    public_values::{chain_digest, AggregationPublicValues},
+    public_values_digest, tree_root, words_to_bytes_le, Scheme, Submitter,
+};
+use shared_types::{
+    AggregationFailure, AggregationInput, AggregationJob, AggregationOutput, AggregationProofMode,
+    DryRunReport, DummyProofInsert, RejectedInput, WorkerHandshake, WsMessage,
+};
 use sp1_sdk::{
-    include_elf, HashableKey, NetworkProverV1, SP1Proof, SP1ProofWithPublicValues, SP1Stdin,
+    include_elf, HashableKey, SP1Proof, SP1ProofKind, SP1ProofWithPublicValues, SP1Stdin,
     SP1VerifyingKey,
 };
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
+mod backend;
+mod config;
+
 pub const AGGREGATOR_ELF: &[u8] = include_elf!("aggregator-program");
 
 async fn handle_insert_dummy_proofs() -> anyhow::Result<Vec<AggregationInput>> {
@@ -36,7 +51,12 @@ async fn handle_insert_dummy_proofs() -> anyhow::Result<Vec<AggregationInput>> {
                 e
             )
         })?;
-        proofs.push(AggregationInput { proof, vk });
+        proofs.push(AggregationInput {
+            proof,
+            vk,
+            submitter: None,
+            salt: None,
+        });
     }
     Ok(proofs)
 }
@@ -44,6 +64,7 @@ async fn handle_insert_dummy_proofs() -> anyhow::Result<Vec<AggregationInput>> {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
+    let config = WorkerConfig::from_env()?;
 
     let url =
         Url::parse("ws://localhost:8080/zkp-aggregator:zkp-aggregator:punctumfix.os").unwrap();
@@ -52,22 +73,77 @@ async fn main() -> anyhow::Result<()> {
 
     println!("WebSocket connected!");
 
+    // All outgoing frames go through one writer task so that jobs can report
+    // back while the read loop keeps serving heartbeats and control messages.
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+    tokio::spawn(async move {
+        while let Some(message) = outgoing_rx.recv().await {
+            if let Err(e) = write.send(message).await {
+                eprintln!("Error in sending message: {}", e);
+                break;
+            }
+        }
+    });
+
+    let backend = build_backend(config.backend);
+    let aggregator_vk = Arc::new(backend.verifying_key(AGGREGATOR_ELF));
+    let self_vkey = format!(
+        "0x{}",
+        hex::encode(words_to_bytes_le(&aggregator_vk.hash_u32()))
+    );
+    println!("Aggregator program vk: {}", aggregator_vk.bytes32());
+    println!("Aggregator program self vkey: {}", self_vkey);
+    // The process refuses to aggregate until these match the contract's PROGRAM_VKEY and SELF_VKEY
+    let handshake = WsMessage::Handshake(WorkerHandshake {
+        program_vkey: aggregator_vk.bytes32(),
+        self_vkey,
+        backend: backend.name().to_string(),
+    });
+    outgoing.send(Message::Binary(serde_json::to_vec(&handshake)?))?;
+    let job_slots = Arc::new(Semaphore::new(config.max_concurrent_jobs));
+    println!(
+        "Accepting up to {} concurrent aggregation jobs on the {} prover",
+        config.max_concurrent_jobs,
+        backend.name()
+    );
+    let max_cycles = config.max_cycles;
+
     while let Some(message) = read.next().await {
         match message {
             Ok(Message::Binary(request)) => {
-                let batch: Vec<AggregationInput> = serde_json::from_slice(&request)?;
-                let proof = process_aggregation(batch).await?;
-                write.send(Message::Binary(proof)).await?;
+                match serde_json::from_slice::<AggregationJob>(&request) {
+                    Ok(job) => {
+                        tokio::spawn(run_job(
+                            job,
+                            backend.clone(),
+                            aggregator_vk.clone(),
+                            max_cycles,
+                            job_slots.clone(),
+                            outgoing.clone(),
+                        ));
+                    }
+                    Err(e) => {
+                        eprintln!("Invalid aggregation job: {}", e);
+                        let failure = failure_frame(&AggregationFailure {
+                            epoch: None,
+                            reason: format!("Invalid aggregation job: {}", e),
+                            rejected: Vec::new(),
+                        });
+                        outgoing.send(Message::Binary(failure))?;
+                    }
+                }
             }
             Ok(Message::Text(request)) => match request.as_str() {
-                "insert_proofs_pls" => {
-                    let proofs = handle_insert_dummy_proofs().await?;
-                    let dummy_insert = DummyProofInsert { proofs };
-                    let serialized_proofs = serde_json::to_vec(&dummy_insert)?;
-                    write.send(Message::Binary(serialized_proofs)).await?;
-                }
+                "insert_proofs_pls" => match handle_insert_dummy_proofs().await {
+                    Ok(proofs) => {
+                        let dummy_insert = DummyProofInsert { proofs };
+                        let serialized_proofs = serde_json::to_vec(&dummy_insert)?;
+                        outgoing.send(Message::Binary(serialized_proofs))?;
+                    }
+                    Err(e) => eprintln!("Failed to load dummy proofs: {}", e),
+                },
                 "send" => {
-                    write.send(Message::Text("pong".to_string())).await?;
+                    outgoing.send(Message::Text("pong".to_string()))?;
                 }
                 _ => {}
             },
@@ -85,31 +161,552 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn process_aggregation(batch: Vec<AggregationInput>) -> anyhow::Result<Vec<u8>> {
-    let network_prover = NetworkProverV1::new();
+/// Runs a job in its own task once a job slot is free, so that an error or a panic
+/// while aggregating is sent back to the process as an `AggregationFailure`
+/// instead of ending the worker.
+async fn run_job(
+    job: AggregationJob,
+    backend: Arc<dyn ProverBackend>,
+    aggregator_vk: Arc<SP1VerifyingKey>,
+    max_cycles: Option<u64>,
+    job_slots: Arc<Semaphore>,
+    outgoing: mpsc::UnboundedSender<Message>,
+) {
+    let epoch = job.epoch;
+    let Ok(_slot) = job_slots.acquire_owned().await else {
+        eprintln!("Job slots closed, dropping job for epoch {}", epoch);
+        return;
+    };
+    println!("Starting aggregation for epoch {}", epoch);
+
+    let aggregation = process_aggregation(job, backend, aggregator_vk, max_cycles);
+    let result = match tokio::spawn(aggregation).await {
+        Ok(result) => result.and_then(|message| {
+            serde_json::to_vec(&message).map_err(|e| AggregationFailure {
+                epoch: Some(epoch),
+                reason: format!("Serialization failed: {}", e),
+                rejected: match &message {
+                    WsMessage::Aggregation(output) => output.rejected.clone(),
+                    WsMessage::DryRun(report) => report.rejected.clone(),
+                    _ => Vec::new(),
+                },
+            })
+        }),
+        Err(e) => Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: format!("Aggregation task failed: {}", e),
+            rejected: Vec::new(),
+        }),
+    };
+    let response = match result {
+        Ok(bytes) => bytes,
+        Err(failure) => {
+            eprintln!("Aggregation for epoch {} failed: {}", epoch, failure.reason);
+            failure_frame(&failure)
+        }
+    };
+    if outgoing.send(Message::Binary(response)).is_err() {
+        eprintln!("Connection closed before epoch {} could be reported", epoch);
+    }
+}
+
+/// A failure as the process reads it. Should serializing it fail, a hand-built frame with
+/// only the epoch still tells the process the job is over.
+fn failure_frame(failure: &AggregationFailure) -> Vec<u8> {
+    serde_json::to_vec(failure).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to serialize the failure of epoch {:?}: {}",
+            failure.epoch, e
+        );
+        let epoch = failure
+            .epoch
+            .map_or_else(|| "null".to_string(), |epoch| epoch.to_string());
+        format!(
+            r#"{{"epoch":{},"reason":"Aggregation failed and its failure could not be serialized"}}"#,
+            epoch
+        )
+        .into_bytes()
+    })
+}
+
+/// Aggregates the job's batch into an `AggregationOutput`, or only executes it into a
+/// `DryRunReport` when the job is a dry run.
+async fn process_aggregation(
+    mut job: AggregationJob,
+    backend: Arc<dyn ProverBackend>,
+    aggregator_vk: Arc<SP1VerifyingKey>,
+    max_cycles: Option<u64>,
+) -> Result<WsMessage, AggregationFailure> {
+    let epoch = job.epoch;
+    if !job.accumulator.is_well_formed() {
+        return Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: "Accumulator peaks do not match its leaf count".to_string(),
+            rejected: Vec::new(),
+        });
+    }
+    let prev_chain = job
+        .prev_aggregate
+        .as_ref()
+        .map(|prev_aggregate| {
+            check_prev_aggregate(backend.as_ref(), &aggregator_vk, prev_aggregate, &job)
+        })
+        .transpose()
+        .map_err(|e| AggregationFailure {
+            epoch: Some(epoch),
+            reason: e.to_string(),
+            rejected: Vec::new(),
+        })?;
+    let (batch, rejected) = verify_inputs(backend.as_ref(), std::mem::take(&mut job.inputs));
+    // A padded tree has a root without leaves, the empty tree
+    if batch.is_empty() && job.scheme.padded_depth.is_none() {
+        return Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: "No valid inputs to aggregate".to_string(),
+            rejected,
+        });
+    }
+
+    let vkeys: Vec<[u32; 8]> = batch.iter().map(|input| input.vk.hash_u32()).collect();
+    let public_values: Vec<Vec<u8>> = batch
+        .iter()
+        .map(|input| input.proof.public_values.to_vec())
+        .collect();
+    let scheme = job.scheme;
+    let submitters = batch_submitters(scheme, &batch).map_err(|e| AggregationFailure {
+        epoch: Some(epoch),
+        reason: e.to_string(),
+        rejected: rejected.clone(),
+    })?;
+    if let Some(depth) = scheme.padded_depth {
+        if !fits_padded(batch.len(), depth) {
+            return Err(AggregationFailure {
+                epoch: Some(epoch),
+                reason: format!(
+                    "{} proofs do not fit in a tree of depth {}",
+                    batch.len(),
+                    depth
+                ),
+                rejected,
+            });
+        }
+    }
+    let salts = batch_salts(&batch);
+    let Some((leaves, expected_root)) =
+        proof_pair_leaves(scheme, &vkeys, &public_values, &submitters, &salts)
+            .and_then(|leaves| tree_root(scheme, &leaves).map(|root| (leaves, root)))
+    else {
+        return Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: "Could not compute the expected Merkle root".to_string(),
+            rejected,
+        });
+    };
+    // The program refuses a batch with a repeated leaf, no need to prove it
+    if let Some((earlier, repeat)) = first_duplicate(&leaves) {
+        return Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: format!(
+                "Input {} has the same leaf as input {}",
+                batch_index(&rejected, repeat),
+                batch_index(&rejected, earlier)
+            ),
+            rejected,
+        });
+    }
+    let mut expected_accumulator = job.accumulator.clone();
+    expected_accumulator.extend(scheme, &leaves);
+    let (prev_chain_digest, chain_start_epoch) = prev_chain
+        .map(|prev| (prev.chainDigest.into(), prev.chainStartEpoch))
+        .unwrap_or(([0u8; 32], epoch));
+    let expected = AggregationPublicValues {
+        epoch,
+        leafCount: batch.len() as u64,
+        prevRoot: job.prev_root.into(),
+        newRoot: expected_root.into(),
+        prevAccumulatorRoot: job.accumulator.root(scheme).into(),
+        accumulatorRoot: expected_accumulator.root(scheme).into(),
+        accumulatorLeafCount: expected_accumulator.leaf_count,
+        selfVkey: words_to_bytes_le(&aggregator_vk.hash_u32()).into(),
+        chainDigest: chain_digest(
+            &prev_chain_digest,
+            epoch,
+            &expected_root,
+            &expected_accumulator.root(scheme),
+        )
+        .into(),
+        chainStartEpoch: chain_start_epoch,
+        leafVersion: scheme.leaf_version as u8,
+        treeHash: scheme.tree_hash as u8,
+        hashedPublicValues: scheme.hashed_public_values,
+        treeDepth: scheme.committed_depth(),
+    };
+
+    let leaf_count = batch.len();
+    // Execute the exact input we are about to prove, so a failing or oversized batch
+    // costs nothing
+    let executed = aggregate_stdin(&job, &aggregator_vk, batch).and_then(|stdin| {
+        let cycles = execute_aggregate(backend.as_ref(), &stdin, &expected, max_cycles)?;
+        Ok((stdin, cycles))
+    });
+    let (stdin, cycles) = match executed {
+        Ok(executed) => executed,
+        Err(e) => {
+            return Err(AggregationFailure {
+                epoch: Some(epoch),
+                reason: e.to_string(),
+                rejected,
+            })
+        }
+    };
+    if job.dry_run {
+        return Ok(WsMessage::DryRun(DryRunReport {
+            epoch,
+            cycles,
+            leaf_count,
+            rejected,
+        }));
+    }
+
+    let result = async {
+        let proof = prove_aggregate(backend.as_ref(), stdin.clone(), job.proof_mode).await?;
+        check_aggregate(backend.as_ref(), &aggregator_vk, &proof, &expected)?;
+        // The next epoch can only verify a compressed aggregate in the program
+        let compressed = match (job.chain, job.proof_mode) {
+            (false, _) => None,
+            (true, AggregationProofMode::Compressed) => Some(proof.clone()),
+            (true, _) => {
+                let compressed =
+                    prove_aggregate(backend.as_ref(), stdin, AggregationProofMode::Compressed)
+                        .await?;
+                check_aggregate(backend.as_ref(), &aggregator_vk, &compressed, &expected)?;
+                Some(compressed)
+            }
+        };
+        anyhow::Ok((proof, compressed))
+    }
+    .await;
+    match result {
+        Ok((proof, compressed)) => Ok(WsMessage::Aggregation(AggregationOutput {
+            epoch,
+            proof,
+            rejected,
+            compressed,
+            cycles: Some(cycles),
+        })),
+        Err(e) => Err(AggregationFailure {
+            epoch: Some(epoch),
+            reason: e.to_string(),
+            rejected,
+        }),
+    }
+}
+
+/// Checks the previous epoch's aggregate before the program verifies it, so a
+/// broken chain fails the job here instead of failing the proof.
+fn check_prev_aggregate(
+    backend: &dyn ProverBackend,
+    aggregator_vk: &SP1VerifyingKey,
+    prev_aggregate: &SP1ProofWithPublicValues,
+    job: &AggregationJob,
+) -> anyhow::Result<AggregationPublicValues> {
+    if !matches!(prev_aggregate.proof, SP1Proof::Compressed(_)) {
+        return Err(anyhow::anyhow!(
+            "Previous aggregate must be a compressed proof to be verified in the program"
+        ));
+    }
+    backend
+        .verify(prev_aggregate, aggregator_vk)
+        .map_err(|e| anyhow::anyhow!("Previous aggregate failed verification: {}", e))?;
+    let prev = AggregationPublicValues::abi_decode(prev_aggregate.public_values.as_slice(), true)
+        .map_err(|e| anyhow::anyhow!("Invalid previous aggregate public values: {:?}", e))?;
+    if prev.epoch >= job.epoch {
+        return Err(anyhow::anyhow!(
+            "Previous aggregate is for epoch {}, not before epoch {}",
+            prev.epoch,
+            job.epoch
+        ));
+    }
+    if prev.leafVersion != job.scheme.leaf_version as u8
+        || prev.treeHash != job.scheme.tree_hash as u8
+        || prev.hashedPublicValues != job.scheme.hashed_public_values
+        || prev.treeDepth != job.scheme.committed_depth()
+    {
+        return Err(anyhow::anyhow!(
+            "Previous aggregate uses leaf version {}, tree hash {}, hashed public values {} and tree depth {}, the job uses {:?}",
+            prev.leafVersion,
+            prev.treeHash,
+            prev.hashedPublicValues,
+            prev.treeDepth,
+            job.scheme
+        ));
+    }
+    if prev.newRoot != job.prev_root || prev.accumulatorRoot != job.accumulator.root(job.scheme) {
+        return Err(anyhow::anyhow!(
+            "Previous aggregate of epoch {} does not match the job's previous root and accumulator",
+            prev.epoch
+        ));
+    }
+    Ok(prev)
+}
+
+/// Verifies the aggregate against the aggregator program vk and makes sure it
+/// commits the epoch, roots and accumulator of the batch we sent, before
+/// it goes back to the process.
+fn check_aggregate(
+    backend: &dyn ProverBackend,
+    aggregator_vk: &SP1VerifyingKey,
+    proof: &SP1ProofWithPublicValues,
+    expected: &AggregationPublicValues,
+) -> anyhow::Result<()> {
+    backend
+        .verify(proof, aggregator_vk)
+        .map_err(|e| anyhow::anyhow!("Aggregated proof failed local verification: {}", e))?;
+    let committed = proof.public_values.as_slice();
+    let expected = AggregationPublicValues::abi_encode(expected);
+    if committed != expected.as_slice() {
+        return Err(anyhow::anyhow!(
+            "Aggregated proof commits 0x{}, expected 0x{}",
+            hex::encode(committed),
+            hex::encode(expected)
+        ));
+    }
+    Ok(())
+}
+
+/// Executes the program on the aggregate input and checks that it commits the expected
+/// public values within `max_cycles`. Returns the cycles it took.
+fn execute_aggregate(
+    backend: &dyn ProverBackend,
+    aggregate_stdin: &SP1Stdin,
+    expected: &AggregationPublicValues,
+    max_cycles: Option<u64>,
+) -> anyhow::Result<u64> {
+    let (public_values, cycles) = backend.execute(AGGREGATOR_ELF, aggregate_stdin)?;
+    println!("Aggregate executed in {} cycles", cycles);
+    let expected = AggregationPublicValues::abi_encode(expected);
+    if public_values.as_slice() != expected.as_slice() {
+        return Err(anyhow::anyhow!(
+            "Executed aggregate commits 0x{}, expected 0x{}",
+            hex::encode(public_values.as_slice()),
+            hex::encode(expected)
+        ));
+    }
+    if let Some(max_cycles) = max_cycles {
+        if cycles > max_cycles {
+            return Err(anyhow::anyhow!(
+                "Batch takes {} cycles to execute, over the MAX_CYCLES limit of {}",
+                cycles,
+                max_cycles
+            ));
+        }
+    }
+    Ok(cycles)
+}
+
+/// Checks every input against its own vk so that one bad submission is
+/// excluded instead of failing the whole aggregation.
+fn verify_inputs(
+    backend: &dyn ProverBackend,
+    inputs: Vec<AggregationInput>,
+) -> (Vec<AggregationInput>, Vec<RejectedInput>) {
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        match prepare_input(backend, input) {
+            Ok(input) => accepted.push(input),
+            Err(e) => {
+                eprintln!("Rejecting input {}: {}", index, e);
+                rejected.push(RejectedInput {
+                    index,
+                    reason: e.to_string(),
+                });
+            }
+        }
+    }
+    (accepted, rejected)
+}
+
+/// Position in the job's inputs of the `index`th input that was not rejected.
+fn batch_index(rejected: &[RejectedInput], index: usize) -> usize {
+    let mut position = index;
+    for rejection in rejected {
+        if rejection.index <= position {
+            position += 1;
+        }
+    }
+    position
+}
+
+/// Verifies an input and brings it into the compressed form the aggregator
+/// program can verify, compressing core proofs on the way.
+fn prepare_input(
+    backend: &dyn ProverBackend,
+    input: AggregationInput,
+) -> anyhow::Result<AggregationInput> {
+    match &input.proof.proof {
+        SP1Proof::Compressed(_) => {
+            backend.verify(&input.proof, &input.vk)?;
+            Ok(input)
+        }
+        SP1Proof::Core(_) => {
+            backend.verify(&input.proof, &input.vk)?;
+            println!(
+                "Compressing core proof with the {} backend...",
+                backend.name()
+            );
+            let proof = backend.compress(&input)?;
+            Ok(AggregationInput {
+                proof,
+                vk: input.vk,
+                submitter: input.submitter,
+                salt: input.salt,
+            })
+        }
+        SP1Proof::Groth16(_) => Err(anyhow::anyhow!(
+            "Groth16 proofs cannot be aggregated, submit a core or compressed proof"
+        )),
+        SP1Proof::Plonk(_) => Err(anyhow::anyhow!(
+            "Plonk proofs cannot be aggregated, submit a core or compressed proof"
+        )),
+    }
+}
+
+/// Submitters of the batch in leaf order, empty unless the leaf version binds them.
+fn batch_submitters(scheme: Scheme, batch: &[AggregationInput]) -> anyhow::Result<Vec<Submitter>> {
+    if !scheme.leaf_version.binds_submitter() {
+        return Ok(Vec::new());
+    }
+    batch
+        .iter()
+        .map(|input| input.submitter)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Leaf version {:?} needs the submitter of every input",
+                scheme.leaf_version
+            )
+        })
+}
+
+/// Salts of the batch in leaf order, empty when no input is salted.
+fn batch_salts(batch: &[AggregationInput]) -> Vec<Option<[u8; 32]>> {
+    if batch.iter().all(|input| input.salt.is_none()) {
+        return Vec::new();
+    }
+    batch.iter().map(|input| input.salt).collect()
+}
+
+/// Program input: the epoch and what it extends, then the batch, with the
+/// proofs in the order the program verifies them.
+fn aggregate_stdin(
+    job: &AggregationJob,
+    aggregator_vk: &SP1VerifyingKey,
+    batch: Vec<AggregationInput>,
+) -> anyhow::Result<SP1Stdin> {
     let mut aggregate_stdin = SP1Stdin::new();
+    aggregate_stdin.write(&job.epoch);
+    aggregate_stdin.write(&job.prev_root);
+    aggregate_stdin.write(&job.scheme);
+    aggregate_stdin.write(&job.accumulator);
+    aggregate_stdin.write(&aggregator_vk.hash_u32());
+    aggregate_stdin.write(
+        &job.prev_aggregate
+            .as_ref()
+            .map(|prev_aggregate| prev_aggregate.public_values.to_vec()),
+    );
 
     let vks: Vec<_> = batch.iter().map(|input| input.vk.hash_u32()).collect();
     aggregate_stdin.write(&vks);
 
+    // Hashed leaves only need the digest the proofs are verified with, which spares the
+    // program hashing the values
     let pub_vals: Vec<_> = batch
         .iter()
-        .map(|input| input.proof.public_values.to_vec())
+        .map(|input| {
+            let public_values = input.proof.public_values.as_slice();
+            if job.scheme.hashed_public_values {
+                public_values_digest(public_values, None).to_vec()
+            } else {
+                public_values.to_vec()
+            }
+        })
         .collect();
     aggregate_stdin.write(&pub_vals);
 
+    aggregate_stdin.write(&batch_submitters(job.scheme, &batch)?);
+    aggregate_stdin.write(&batch_salts(&batch));
+
     for input in batch {
         let SP1Proof::Compressed(proof) = input.proof.proof else {
-            panic!()
+            return Err(anyhow::anyhow!("Only compressed proofs can be aggregated"));
         };
         aggregate_stdin.write_proof(*proof, input.vk.vk);
     }
-    println!("Proving...");
-    let proof = network_prover
-        .prove(AGGREGATOR_ELF, aggregate_stdin, ProofMode::Groth16, None)
-        .await
-        .map_err(|e| anyhow::anyhow!("Proving failed: {}", e))?;
+    if let Some(prev_aggregate) = &job.prev_aggregate {
+        let SP1Proof::Compressed(proof) = &prev_aggregate.proof else {
+            return Err(anyhow::anyhow!(
+                "Only a compressed aggregate can be chained"
+            ));
+        };
+        aggregate_stdin.write_proof(*proof.clone(), aggregator_vk.vk.clone());
+    }
+    Ok(aggregate_stdin)
+}
+
+async fn prove_aggregate(
+    backend: &dyn ProverBackend,
+    aggregate_stdin: SP1Stdin,
+    proof_mode: AggregationProofMode,
+) -> anyhow::Result<SP1ProofWithPublicValues> {
+    let kind = match proof_mode {
+        AggregationProofMode::Groth16 => SP1ProofKind::Groth16,
+        AggregationProofMode::Plonk => SP1ProofKind::Plonk,
+        AggregationProofMode::Compressed => SP1ProofKind::Compressed,
+    };
+    println!(
+        "Proving a {:?} aggregate with the {} backend...",
+        proof_mode,
+        backend.name()
+    );
+    backend.prove(AGGREGATOR_ELF, aggregate_stdin, kind).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejected(indices: &[usize]) -> Vec<RejectedInput> {
+        indices
+            .iter()
+            .map(|&index| RejectedInput {
+                index,
+                reason: "Verification failed".to_string(),
+            })
+            .collect()
+    }
 
-    serde_json::to_vec(&AggregationOutput { proof })
-        .map_err(|e| anyhow::anyhow!("Serialization failed: {}", e))
+    #[test]
+    fn batch_index_without_rejections_is_the_index() {
+        assert_eq!(batch_index(&[], 0), 0);
+        assert_eq!(batch_index(&[], 3), 3);
+    }
+
+    #[test]
+    fn batch_index_skips_rejected_inputs() {
+        // Inputs 1 and 2 were rejected, so the batch is inputs 0, 3, 4
+        let rejected = rejected(&[1, 2]);
+        assert_eq!(batch_index(&rejected, 0), 0);
+        assert_eq!(batch_index(&rejected, 1), 3);
+        assert_eq!(batch_index(&rejected, 2), 4);
+    }
+
+    #[test]
+    fn batch_index_skips_leading_and_trailing_rejections() {
+        // Inputs 0, 2 and 5 were rejected, so the batch is inputs 1, 3, 4
+        let rejected = rejected(&[0, 2, 5]);
+        assert_eq!(batch_index(&rejected, 0), 1);
+        assert_eq!(batch_index(&rejected, 1), 3);
+        assert_eq!(batch_index(&rejected, 2), 4);
+    }
 }
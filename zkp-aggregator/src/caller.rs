@@ -7,9 +7,236 @@ use alloy::{
     signers::local::PrivateKeySigner,
 };
 use alloy_primitives::{FixedBytes, U256};
-use kinode_process_lib::eth::{Address as EthAddress, EthError, Provider};
+use kinode_process_lib::{
+    eth::{Address as EthAddress, BlockId, BlockNumberOrTag, EthError, Provider},
+    kiprintln,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
+const GWEI: u128 = 1_000_000_000;
+
+// How gas and fees are set on a chain. `for_chain` has defaults per chain, the caps can
+// be overridden in `.env`.
+#[derive(Debug, Clone, Copy)]
+pub struct FeePolicy {
+    // Percent added on top of eth_estimateGas
+    pub gas_margin_percent: u128,
+    // Blocks of eth_feeHistory to sample, and the reward percentile to pay as priority fee
+    pub fee_history_blocks: u64,
+    pub priority_fee_percentile: f64,
+    // The max fee covers this many times the next base fee, so a few full blocks don't strand us
+    pub base_fee_multiplier: u128,
+    pub min_priority_fee_per_gas: u128,
+    // Estimates above this are refused, fees above these are capped
+    pub max_gas_limit: u128,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+impl FeePolicy {
+    pub fn for_chain(chain_id: u64) -> Self {
+        let mainnet = Self {
+            gas_margin_percent: 20,
+            fee_history_blocks: 10,
+            priority_fee_percentile: 50.0,
+            base_fee_multiplier: 2,
+            min_priority_fee_per_gas: GWEI / 10,
+            max_gas_limit: 3_000_000,
+            max_fee_per_gas: 200 * GWEI,
+            max_priority_fee_per_gas: 5 * GWEI,
+        };
+        match chain_id {
+            // OP stack chains (Optimism, Base and their testnets) have tiny, steady fees
+            10 | 8453 | 11155420 | 84532 => Self {
+                min_priority_fee_per_gas: 1_000_000,
+                max_fee_per_gas: 5 * GWEI,
+                max_priority_fee_per_gas: GWEI / 10,
+                ..mainnet
+            },
+            // Anvil may run without a base fee, so there is no history worth sampling
+            31337 => Self {
+                fee_history_blocks: 1,
+                min_priority_fee_per_gas: 0,
+                max_fee_per_gas: 1_000 * GWEI,
+                max_priority_fee_per_gas: 1_000 * GWEI,
+                ..mainnet
+            },
+            _ => mainnet,
+        }
+    }
+}
+
+// Gas and fees of an EIP-1559 transaction
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxFees {
+    pub gas_limit: u128,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+// What we keep of a transaction receipt
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct TxReceipt {
+    pub block_number: u64,
+    pub gas_used: u128,
+    pub success: bool,
+}
+
+// A transaction we sent that is not known to be mined yet, with what it takes to sign it again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingTx {
+    pub epoch: u64,
+    // Latest transaction with this nonce
+    pub tx_hash: [u8; 32],
+    // Earlier transactions with this nonce it replaced, oldest first. Any of them may still land.
+    #[serde(default)]
+    pub replaced: Vec<[u8; 32]>,
+    #[serde(default)]
+    pub to: String,
+    #[serde(default)]
+    pub call: Vec<u8>,
+    #[serde(default)]
+    pub value: U256,
+    #[serde(default)]
+    pub fees: Option<TxFees>,
+    // Block number when the latest transaction was sent
+    #[serde(default)]
+    pub sent_at_block: Option<u64>,
+}
+
+impl PendingTx {
+    // Every transaction with this nonce, latest first
+    pub fn hashes(&self) -> impl Iterator<Item = &[u8; 32]> {
+        std::iter::once(&self.tx_hash).chain(self.replaced.iter().rev())
+    }
+}
+
+// Gas limit for an estimate, with the policy's margin. While an earlier transaction of ours is
+// outstanding the call may only succeed on top of it, so a failed estimate falls back to the
+// policy's cap instead of failing the send.
+fn gas_limit(
+    estimate: anyhow::Result<u128>,
+    policy: &FeePolicy,
+    after_pending: bool,
+) -> anyhow::Result<u128> {
+    let estimate = match estimate {
+        Ok(estimate) => estimate,
+        Err(_) if after_pending => return Ok(policy.max_gas_limit),
+        Err(e) => return Err(e),
+    };
+    let gas_limit = estimate * (100 + policy.gas_margin_percent) / 100;
+    if gas_limit > policy.max_gas_limit {
+        return Err(anyhow::anyhow!(
+            "Transaction needs {} gas, over the limit of {}",
+            gas_limit,
+            policy.max_gas_limit
+        ));
+    }
+    Ok(gas_limit)
+}
+
+// Smallest fee the replacement rules accept over `fee`: at least 10% more
+fn bump_fee(fee: u128) -> u128 {
+    fee + fee.div_ceil(10).max(1)
+}
+
+// Hands out the nonces of our wallet so back-to-back transactions neither collide nor
+// leave gaps. Lives in the process state so pending transactions survive a restart.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct NonceManager {
+    // Nonce of the next transaction, unknown until synced with the chain
+    next_nonce: Option<u64>,
+    // Outstanding transactions by nonce
+    pending: BTreeMap<u64, PendingTx>,
+}
+
+impl NonceManager {
+    // Take the chain's word for the next nonce. Pending transactions below the mined count
+    // stay for their receipts to settle them, the ones at or above the chain's pending count
+    // were dropped and are returned so their epochs can be failed.
+    pub fn resync(&mut self, caller: &Caller) -> anyhow::Result<Vec<(u64, PendingTx)>> {
+        let mined = caller.transaction_count(BlockId::latest())?;
+        let next = caller.transaction_count(BlockId::pending())?.max(mined);
+        let dropped = self.resync_to(next);
+        for (nonce, pending) in &dropped {
+            kiprintln!(
+                "Transaction 0x{} of epoch {} with nonce {} was dropped",
+                hex::encode(pending.tx_hash),
+                pending.epoch,
+                nonce
+            );
+        }
+        Ok(dropped)
+    }
+
+    // Continue from `next` and return the pending transactions at or above it
+    fn resync_to(&mut self, next: u64) -> Vec<(u64, PendingTx)> {
+        self.next_nonce = Some(next);
+        self.pending.split_off(&next).into_iter().collect()
+    }
+
+    pub fn next_nonce(&self) -> Option<u64> {
+        self.next_nonce
+    }
+
+    // Resync before the next transaction
+    pub fn invalidate(&mut self) {
+        self.next_nonce = None;
+    }
+
+    // Record a transaction sent with the next nonce and move past it
+    pub fn record(&mut self, nonce: u64, pending: PendingTx) {
+        self.pending.insert(nonce, pending);
+        self.next_nonce = Some(self.next_nonce.unwrap_or_default().max(nonce + 1));
+    }
+
+    pub fn get(&self, nonce: u64) -> Option<&PendingTx> {
+        self.pending.get(&nonce)
+    }
+
+    // Start the deadline of a transaction sent before we knew the block
+    pub fn set_sent_at_block(&mut self, nonce: u64, block: u64) {
+        if let Some(pending) = self.pending.get_mut(&nonce) {
+            pending.sent_at_block = Some(block);
+        }
+    }
+
+    // Record a transaction that replaces the pending one with the same nonce
+    pub fn replace(
+        &mut self,
+        nonce: u64,
+        tx_hash: [u8; 32],
+        fees: TxFees,
+        sent_at_block: Option<u64>,
+    ) {
+        if let Some(pending) = self.pending.get_mut(&nonce) {
+            pending.replaced.push(pending.tx_hash);
+            pending.tx_hash = tx_hash;
+            pending.fees = Some(fees);
+            pending.sent_at_block = sent_at_block;
+        }
+    }
+
+    // Forget a transaction once it is settled
+    pub fn complete(&mut self, nonce: u64) -> Option<PendingTx> {
+        self.pending.remove(&nonce)
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = (&u64, &PendingTx)> {
+        self.pending.iter()
+    }
+
+    // Outstanding transaction of an epoch, if any
+    pub fn pending_for_epoch(&self, epoch: u64) -> Option<(u64, &PendingTx)> {
+        self.pending
+            .iter()
+            .find(|(_, pending)| pending.epoch == epoch)
+            .map(|(nonce, pending)| (*nonce, pending))
+    }
+}
+
 pub struct Caller {
     pub provider: Provider,
     pub signer: PrivateKeySigner,
@@ -31,6 +258,118 @@ impl Caller {
         })
     }
 
+    // Number of transactions our wallet has sent as of `block`
+    pub fn transaction_count(&self, block: BlockId) -> anyhow::Result<u64> {
+        self.provider
+            .get_transaction_count(self.signer.address(), Some(block))
+            .map(|count| count.to::<u64>())
+            .map_err(|e| anyhow::anyhow!("Error getting transaction count: {:?}", e))
+    }
+
+    pub fn block_number(&self) -> anyhow::Result<u64> {
+        self.provider
+            .get_block_number()
+            .map_err(|e| anyhow::anyhow!("Error getting the block number: {:?}", e))
+    }
+
+    // Receipt of a mined transaction, `None` while it is not in a block
+    pub fn receipt(&self, tx_hash: [u8; 32]) -> anyhow::Result<Option<TxReceipt>> {
+        let receipt = self
+            .provider
+            .get_transaction_receipt(FixedBytes::from(tx_hash))
+            .map_err(|e| anyhow::anyhow!("Error getting receipt: {:?}", e))?;
+        Ok(receipt.and_then(|receipt| {
+            Some(TxReceipt {
+                block_number: receipt.block_number?,
+                gas_used: receipt.gas_used,
+                success: receipt.status(),
+            })
+        }))
+    }
+
+    // Gas and fees for a call, from eth_estimateGas on the pending block and the recent fee
+    // history within the policy. Fails when the call would revert or needs more gas than the
+    // policy allows, unless it comes `after_pending` transactions of ours it may depend on.
+    pub fn tx_fees(
+        &self,
+        call: &[u8],
+        contract_address: &str,
+        policy: &FeePolicy,
+        after_pending: bool,
+    ) -> anyhow::Result<TxFees> {
+        let to = EthAddress::from_str(contract_address)
+            .map_err(|_| anyhow::anyhow!("Invalid contract address"))?;
+        let tx = TransactionRequest::default()
+            .from(self.signer.address())
+            .to(to)
+            .input(call.to_vec().into());
+        let estimate = self
+            .provider
+            .estimate_gas(tx, Some(BlockId::pending()))
+            .map(|estimate| estimate.to::<u128>())
+            .map_err(|e| anyhow::anyhow!("Error estimating gas: {:?}", e));
+        if let (Err(e), true) = (&estimate, after_pending) {
+            kiprintln!(
+                "Estimating gas behind our pending transactions failed, using the cap of {}: {:?}",
+                policy.max_gas_limit,
+                e
+            );
+        }
+        let gas_limit = gas_limit(estimate, policy, after_pending)?;
+
+        let (base_fee, priority_fee) = self.fee_estimate(policy)?;
+        let max_priority_fee_per_gas = priority_fee
+            .max(policy.min_priority_fee_per_gas)
+            .min(policy.max_priority_fee_per_gas);
+        let max_fee_per_gas = (base_fee * policy.base_fee_multiplier + max_priority_fee_per_gas)
+            .min(policy.max_fee_per_gas);
+        if max_fee_per_gas < base_fee {
+            kiprintln!(
+                "Base fee {} is over the max fee of {}, the transaction waits until it drops",
+                base_fee,
+                policy.max_fee_per_gas
+            );
+        }
+        Ok(TxFees {
+            gas_limit,
+            max_fee_per_gas,
+            max_priority_fee_per_gas: max_priority_fee_per_gas.min(max_fee_per_gas),
+        })
+    }
+
+    // Next base fee and the priority fee recent blocks paid at the policy's percentile.
+    // Falls back to the latest block's base fee where eth_feeHistory is not served.
+    fn fee_estimate(&self, policy: &FeePolicy) -> anyhow::Result<(u128, u128)> {
+        match self.provider.get_fee_history(
+            U256::from(policy.fee_history_blocks),
+            BlockNumberOrTag::Latest,
+            vec![policy.priority_fee_percentile],
+        ) {
+            // The last base fee is the one of the next block
+            Ok(history) => {
+                let base_fee = history.base_fee_per_gas.last().copied().unwrap_or_default();
+                let mut rewards: Vec<u128> = history
+                    .reward
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|block| block.first().copied())
+                    .collect();
+                rewards.sort_unstable();
+                let priority_fee = rewards.get(rewards.len() / 2).copied().unwrap_or_default();
+                Ok((base_fee, priority_fee))
+            }
+            Err(e) => {
+                kiprintln!("eth_feeHistory failed, using the latest base fee: {:?}", e);
+                let block = self
+                    .provider
+                    .get_block_by_number(BlockNumberOrTag::Latest, false)
+                    .map_err(|e| anyhow::anyhow!("Error getting the latest block: {:?}", e))?
+                    .ok_or_else(|| anyhow::anyhow!("No latest block"))?;
+                Ok((block.header.base_fee_per_gas.unwrap_or_default(), 0))
+            }
+        }
+    }
+
     pub fn tx_req(
         &self,
         call: Vec<u8>,
@@ -86,56 +425,248 @@ impl Caller {
             Err(e) => Err(anyhow::anyhow!("Error sending transaction: {:?}", e)),
         }
     }
+    // Send with the next nonce of `nonces` and record the transaction as pending for `epoch`
     pub fn send_tx(
         &self,
+        nonces: &mut NonceManager,
+        epoch: u64,
         call: Vec<u8>,
         contract_address: &str,
-        gas_limit: u128,
-        max_fee_per_gas: u128,
-        max_priority_fee_per_gas: u128,
+        fees: &TxFees,
         value: U256,
         chain_id: u64,
     ) -> anyhow::Result<(FixedBytes<32>, u64)> {
-        // get nonce
-        let tx_count = self
-            .provider
-            .get_transaction_count(self.signer.address(), None);
-        let mut nonce = 0; // ignore this error
-        if let Ok(tx_count) = tx_count {
-            nonce = tx_count.to::<u64>();
-        } else {
-            return Err(anyhow::anyhow!("Error getting transaction count"));
+        let Some(nonce) = nonces.next_nonce() else {
+            return Err(anyhow::anyhow!("Nonces are not synced with the chain"));
+        };
+        let sent_at_block = self.block_number().ok();
+        let result = self.send_tx_with_nonce(
+            nonce,
+            call.clone(),
+            contract_address,
+            fees.gas_limit,
+            fees.max_fee_per_gas,
+            fees.max_priority_fee_per_gas,
+            value,
+            chain_id,
+        );
+        match result {
+            Ok((tx_hash, nonce)) => {
+                nonces.record(
+                    nonce,
+                    PendingTx {
+                        epoch,
+                        tx_hash: tx_hash.into(),
+                        replaced: Vec::new(),
+                        to: contract_address.to_string(),
+                        call,
+                        value,
+                        fees: Some(*fees),
+                        sent_at_block,
+                    },
+                );
+                Ok((tx_hash, nonce))
+            }
+            Err(e) => {
+                // Our nonce may be stale, e.g. the wallet was used elsewhere
+                nonces.invalidate();
+                Err(e)
+            }
         }
+    }
 
-        // get contract address
-        let to;
-        if let Ok(address) = EthAddress::from_str(contract_address) {
-            to = address;
-        } else {
-            return Err(anyhow::anyhow!("Invalid contract address"));
+    // Sign the pending transaction with `nonce` again with fees bumped past the replacement
+    // rules, and at least what the fee history asks for now, so it takes the place of the
+    // stuck one. Fails rather than go over the policy's caps.
+    pub fn replace_tx(
+        &self,
+        nonces: &mut NonceManager,
+        nonce: u64,
+        policy: &FeePolicy,
+        chain_id: u64,
+    ) -> anyhow::Result<FixedBytes<32>> {
+        let pending = nonces
+            .get(nonce)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No pending transaction with nonce {}", nonce))?;
+        let Some(fees) = pending.fees.filter(|_| !pending.call.is_empty()) else {
+            return Err(anyhow::anyhow!(
+                "Transaction with nonce {} was not recorded with what it takes to replace it",
+                nonce
+            ));
+        };
+        let (base_fee, priority_fee) = self.fee_estimate(policy)?;
+        let max_priority_fee_per_gas = bump_fee(fees.max_priority_fee_per_gas).max(priority_fee);
+        let max_fee_per_gas = bump_fee(fees.max_fee_per_gas)
+            .max(base_fee * policy.base_fee_multiplier + max_priority_fee_per_gas);
+        if max_fee_per_gas > policy.max_fee_per_gas
+            || max_priority_fee_per_gas > policy.max_priority_fee_per_gas
+        {
+            return Err(anyhow::anyhow!(
+                "Replacing needs a max fee of {} and priority fee of {}, over the caps of {} and {}",
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                policy.max_fee_per_gas,
+                policy.max_priority_fee_per_gas
+            ));
         }
-
-        let mut tx = TxEip1559 {
-            chain_id: chain_id,
-            nonce: nonce,
-            to: TxKind::Call(to),
-            gas_limit: gas_limit,
-            max_fee_per_gas: max_fee_per_gas,
-            max_priority_fee_per_gas: max_priority_fee_per_gas,
-            input: call.into(),
-            value: value,
-            ..Default::default()
+        let bumped = TxFees {
+            gas_limit: fees.gas_limit,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
         };
+        let sent_at_block = self.block_number().ok();
+        let (tx_hash, _) = self.send_tx_with_nonce(
+            nonce,
+            pending.call,
+            &pending.to,
+            bumped.gas_limit,
+            bumped.max_fee_per_gas,
+            bumped.max_priority_fee_per_gas,
+            pending.value,
+            chain_id,
+        )?;
+        nonces.replace(nonce, tx_hash.into(), bumped, sent_at_block);
+        Ok(tx_hash)
+    }
+}
 
-        let sig = self.signer.sign_transaction_sync(&mut tx).unwrap();
-        let signed = TxEnvelope::from(tx.into_signed(sig));
-        let mut buf = vec![];
-        signed.encode_2718(&mut buf);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let result = self.provider.send_raw_transaction(buf.into());
-        match result {
-            Ok(tx_hash) => Ok((tx_hash, nonce)),
-            Err(e) => Err(anyhow::anyhow!("Error sending transaction: {:?}", e)),
+    #[test]
+    fn gas_limit_adds_the_margin() {
+        let policy = FeePolicy::for_chain(1);
+        assert_eq!(gas_limit(Ok(100_000), &policy, false).unwrap(), 120_000);
+        assert_eq!(gas_limit(Ok(100_000), &policy, true).unwrap(), 120_000);
+    }
+
+    #[test]
+    fn gas_limit_refuses_estimates_over_the_cap() {
+        let policy = FeePolicy::for_chain(1);
+        assert!(gas_limit(Ok(policy.max_gas_limit), &policy, false).is_err());
+        assert!(gas_limit(Ok(policy.max_gas_limit), &policy, true).is_err());
+    }
+
+    // The next epoch's prevRoot is the root of the one still pending, so the estimate reverts
+    #[test]
+    fn failed_estimate_behind_a_pending_transaction_uses_the_cap() {
+        let policy = FeePolicy::for_chain(1);
+        let reverted = || Err(anyhow::anyhow!("execution reverted"));
+        assert_eq!(
+            gas_limit(reverted(), &policy, true).unwrap(),
+            policy.max_gas_limit
+        );
+        assert!(gas_limit(reverted(), &policy, false).is_err());
+    }
+
+    fn pending_tx(epoch: u64, tx_hash: u8) -> PendingTx {
+        PendingTx {
+            epoch,
+            tx_hash: [tx_hash; 32],
+            replaced: Vec::new(),
+            to: String::new(),
+            call: Vec::new(),
+            value: U256::ZERO,
+            fees: None,
+            sent_at_block: Some(100),
+        }
+    }
+
+    fn fees(max_fee_per_gas: u128) -> TxFees {
+        TxFees {
+            gas_limit: 100_000,
+            max_fee_per_gas,
+            max_priority_fee_per_gas: max_fee_per_gas / 10,
+        }
+    }
+
+    #[test]
+    fn record_moves_past_the_nonce() {
+        let mut nonces = NonceManager::default();
+        assert_eq!(nonces.next_nonce(), None);
+        nonces.record(5, pending_tx(1, 1));
+        assert_eq!(nonces.next_nonce(), Some(6));
+        // A late record of a lower nonce does not move the next one back
+        nonces.record(3, pending_tx(2, 2));
+        assert_eq!(nonces.next_nonce(), Some(6));
+        assert_eq!(nonces.get(3).unwrap().epoch, 2);
+        assert_eq!(nonces.pending().count(), 2);
+    }
+
+    #[test]
+    fn replace_keeps_every_hash() {
+        let mut nonces = NonceManager::default();
+        nonces.record(0, pending_tx(1, 1));
+        nonces.replace(0, [2; 32], fees(20), Some(110));
+        nonces.replace(0, [3; 32], fees(30), None);
+        let pending = nonces.get(0).unwrap();
+        assert_eq!(pending.tx_hash, [3; 32]);
+        assert_eq!(pending.replaced, vec![[1; 32], [2; 32]]);
+        assert_eq!(pending.fees.unwrap().max_fee_per_gas, 30);
+        assert_eq!(pending.sent_at_block, None);
+        assert_eq!(
+            pending.hashes().collect::<Vec<_>>(),
+            vec![&[3; 32], &[2; 32], &[1; 32]]
+        );
+        // Nothing to replace without a pending transaction
+        nonces.replace(1, [4; 32], fees(40), None);
+        assert!(nonces.get(1).is_none());
+    }
+
+    #[test]
+    fn complete_forgets_the_transaction() {
+        let mut nonces = NonceManager::default();
+        nonces.record(0, pending_tx(1, 1));
+        nonces.record(1, pending_tx(2, 2));
+        assert_eq!(nonces.complete(0).unwrap().epoch, 1);
+        assert!(nonces.complete(0).is_none());
+        assert_eq!(
+            nonces
+                .pending()
+                .map(|(nonce, _)| *nonce)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(nonces.next_nonce(), Some(2));
+    }
+
+    #[test]
+    fn pending_for_epoch_finds_its_nonce() {
+        let mut nonces = NonceManager::default();
+        nonces.record(4, pending_tx(7, 1));
+        nonces.record(5, pending_tx(8, 2));
+        assert_eq!(nonces.pending_for_epoch(8).map(|(nonce, _)| nonce), Some(5));
+        assert!(nonces.pending_for_epoch(9).is_none());
+        nonces.complete(5);
+        assert!(nonces.pending_for_epoch(8).is_none());
+    }
+
+    #[test]
+    fn resync_drops_transactions_the_chain_does_not_know() {
+        let mut nonces = NonceManager::default();
+        for nonce in 0..4 {
+            nonces.record(nonce, pending_tx(nonce + 10, nonce as u8));
         }
+        nonces.invalidate();
+        assert_eq!(nonces.next_nonce(), None);
+        let dropped = nonces.resync_to(2);
+        assert_eq!(
+            dropped
+                .iter()
+                .map(|(nonce, pending)| (*nonce, pending.epoch))
+                .collect::<Vec<_>>(),
+            vec![(2, 12), (3, 13)]
+        );
+        // Mined ones stay for their receipts
+        assert_eq!(
+            nonces
+                .pending()
+                .map(|(nonce, _)| *nonce)
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(nonces.next_nonce(), Some(2));
     }
 }
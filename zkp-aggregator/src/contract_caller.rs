@@ -1,10 +1,11 @@
-use crate::caller::Caller;
+use crate::caller::{Caller, FeePolicy, NonceManager};
 use crate::CURRENT_CHAIN_ID;
-use alloy_primitives::U256;
-use alloy_sol_types::{sol, SolCall};
+use alloy_primitives::{FixedBytes, U256};
+use alloy_sol_types::{sol, SolCall, SolType};
 use kinode_process_lib::kiprintln;
 use serde::{Deserialize, Serialize};
-use shared_types::AggregationOutput;
+use shared_merkle::public_values::AggregationPublicValues;
+use shared_types::{AggregationOutput, AggregationProofMode};
 /* ABI import */
 sol!(
     #[allow(missing_docs)]
@@ -17,14 +18,78 @@ sol!(
 pub struct ContractCaller {
     pub caller: Caller,
     pub contract_address: String,
+    // Proof modes the verifier behind the contract can check
+    pub supported_proof_modes: Vec<AggregationProofMode>,
+    // How gas and fees are set on the target chain
+    pub fee_policy: FeePolicy,
 }
 
 impl ContractCaller {
+    // Same decoding the contract does with abi.decode(_publicValues, (AggregationPublicValues))
+    pub fn decode_public_values(public_values: &[u8]) -> anyhow::Result<AggregationPublicValues> {
+        AggregationPublicValues::abi_decode(public_values, true)
+            .map_err(|e| anyhow::anyhow!("Invalid aggregation public values: {:?}", e))
+    }
+
+    // Vk of the aggregator program the contract accepts proofs of, the same bytes32 as `vk.bytes32()`
+    pub fn program_vkey(&self) -> anyhow::Result<[u8; 32]> {
+        let call = SP1AggregateVerifier::PROGRAM_VKEYCall {}.abi_encode();
+        let returned = self
+            .caller
+            .tx_req(call, &self.contract_address)
+            .map_err(|e| anyhow::anyhow!("Error calling PROGRAM_VKEY: {:?}", e))?;
+        let decoded = SP1AggregateVerifier::PROGRAM_VKEYCall::abi_decode_returns(&returned, true)
+            .map_err(|e| anyhow::anyhow!("Invalid PROGRAM_VKEY return data: {:?}", e))?;
+        Ok(decoded._0.into())
+    }
+
+    // Vk of the aggregator program as its aggregates commit it in `selfVkey`
+    pub fn self_vkey(&self) -> anyhow::Result<[u8; 32]> {
+        let call = SP1AggregateVerifier::SELF_VKEYCall {}.abi_encode();
+        let returned = self
+            .caller
+            .tx_req(call, &self.contract_address)
+            .map_err(|e| anyhow::anyhow!("Error calling SELF_VKEY: {:?}", e))?;
+        let decoded = SP1AggregateVerifier::SELF_VKEYCall::abi_decode_returns(&returned, true)
+            .map_err(|e| anyhow::anyhow!("Invalid SELF_VKEY return data: {:?}", e))?;
+        Ok(decoded._0.into())
+    }
+
+    // Send the stuck transaction with `nonce` again with bumped fees
+    pub fn replace_stuck_tx(
+        &self,
+        nonces: &mut NonceManager,
+        nonce: u64,
+    ) -> anyhow::Result<FixedBytes<32>> {
+        self.caller
+            .replace_tx(nonces, nonce, &self.fee_policy, *CURRENT_CHAIN_ID)
+    }
+
     pub fn verify_aggregate_proof_and_update_root(
         &self,
         output: AggregationOutput,
-    ) -> anyhow::Result<()> {
-        kiprintln!("Starting transaction...");
+        nonces: &mut NonceManager,
+    ) -> anyhow::Result<(FixedBytes<32>, u64)> {
+        let Some(mode) = AggregationProofMode::of(&output.proof.proof) else {
+            return Err(anyhow::anyhow!("Core proofs cannot be verified on chain"));
+        };
+        if !mode.is_onchain_verifiable() || !self.supported_proof_modes.contains(&mode) {
+            return Err(anyhow::anyhow!(
+                "{:?} proofs cannot be checked by the target verifier (supports {:?})",
+                mode,
+                self.supported_proof_modes
+            ));
+        }
+
+        let values = Self::decode_public_values(output.proof.public_values.as_slice())?;
+        kiprintln!(
+            "Starting transaction for epoch {} ({} leaves, root {}, accumulator {} over {} leaves)...",
+            values.epoch,
+            values.leafCount,
+            values.newRoot,
+            values.accumulatorRoot,
+            values.accumulatorLeafCount
+        );
 
         let public_values_hex = output.proof.public_values.to_vec();
         let proof_bytes_hex = output.proof.bytes();
@@ -35,18 +100,33 @@ impl ContractCaller {
         }
         .abi_encode();
 
+        // Estimating also catches a transaction that would revert, before it costs gas. While an
+        // earlier aggregate of ours is pending this one extends its root, so it may only pass
+        // on top of it.
+        let after_pending = nonces.pending().next().is_some();
+        let fees = self.caller.tx_fees(
+            &call,
+            &self.contract_address,
+            &self.fee_policy,
+            after_pending,
+        )?;
+        kiprintln!("Sending with {:?}", fees);
         match self.caller.send_tx(
+            nonces,
+            values.epoch,
             call,
             &self.contract_address,
-            2_000_000,
-            1_000_000,
-            100_000,
+            &fees,
             U256::from(0),
             *CURRENT_CHAIN_ID,
         ) {
-            Ok((tx_hash, _nonce)) => {
-                kiprintln!("Transaction sent successfully! Hash: {}", tx_hash);
-                Ok(())
+            Ok((tx_hash, nonce)) => {
+                kiprintln!(
+                    "Transaction sent successfully! Hash: {} (nonce {})",
+                    tx_hash,
+                    nonce
+                );
+                Ok((tx_hash, nonce))
             }
             Err(e) => {
                 kiprintln!("Transaction failed with error: {:?}", e);
@@ -1,3 +1,4 @@
+use alloy_sol_types::SolType;
 use dotenvy::from_read;
 use kinode_process_lib::{
     await_message, call_init, get_blob, get_typed_state,
@@ -8,13 +9,17 @@ use kinode_process_lib::{
     Address, LazyLoadBlob, Message,
 };
 use lazy_static::lazy_static;
-use shared_types::{AggregationInput, AggregationOutput, WsMessage};
+use shared_merkle::{
+    public_values::AggregationPublicValues, words_to_bytes_le, LeafVersion, Scheme, TreeHash,
+    MAX_PADDED_DEPTH,
+};
+use shared_types::{AggregationJob, AggregationOutput, AggregationProofMode, WsMessage};
 use std::env;
 use std::io::Cursor;
 pub mod caller;
 pub mod contract_caller;
 pub mod structs;
-use caller::Caller;
+use caller::{Caller, FeePolicy};
 use contract_caller::ContractCaller;
 use structs::*;
 lazy_static! {
@@ -42,6 +47,122 @@ lazy_static! {
         from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
         env::var("CURRENT_RPC_URL").expect("RPC_URL must be set")
     };
+    // Proof mode requested for this lane's aggregates, Groth16 by default
+    pub static ref AGGREGATION_PROOF_MODE: AggregationProofMode = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("AGGREGATION_PROOF_MODE")
+            .map(|mode| mode.parse().expect("Invalid AGGREGATION_PROOF_MODE"))
+            .unwrap_or_default()
+    };
+    // Proof modes the verifier behind the contract can check, Groth16 by default
+    pub static ref VERIFIER_PROOF_MODES: Vec<AggregationProofMode> = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("VERIFIER_PROOF_MODES")
+            .map(|modes| {
+                modes
+                    .split(',')
+                    .map(|mode| mode.parse().expect("Invalid VERIFIER_PROOF_MODES"))
+                    .collect()
+            })
+            .unwrap_or_else(|_| vec![AggregationProofMode::Groth16])
+    };
+    // Gas and fee policy of the target chain, with the caps `.env` overrides
+    pub static ref FEE_POLICY: FeePolicy = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        let mut policy = FeePolicy::for_chain(*CURRENT_CHAIN_ID);
+        if let Some(margin) = env_value("GAS_MARGIN_PERCENT") {
+            policy.gas_margin_percent = margin.parse().expect("Invalid GAS_MARGIN_PERCENT");
+        }
+        if let Some(limit) = env_value("MAX_GAS_LIMIT") {
+            policy.max_gas_limit = limit.parse().expect("Invalid MAX_GAS_LIMIT");
+        }
+        if let Some(fee) = env_value("MAX_FEE_PER_GAS") {
+            policy.max_fee_per_gas = fee.parse().expect("Invalid MAX_FEE_PER_GAS");
+        }
+        if let Some(fee) = env_value("MAX_PRIORITY_FEE_PER_GAS") {
+            policy.max_priority_fee_per_gas =
+                fee.parse().expect("Invalid MAX_PRIORITY_FEE_PER_GAS");
+        }
+        policy
+    };
+    // How many blocks deep our transaction must be, counting its own block, before its epoch
+    // counts as confirmed, 1 on anvil and 3 elsewhere by default
+    pub static ref CONFIRMATIONS: u64 = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env_value("CONFIRMATIONS")
+            .map(|confirmations| confirmations.parse().expect("Invalid CONFIRMATIONS"))
+            .unwrap_or(if *CURRENT_CHAIN_ID == 31337 { 1 } else { 3 })
+    };
+    // Minutes the extension has to send back an aggregate before its epoch is requeued, 60 by
+    // default
+    pub static ref AGGREGATION_DEADLINE_MINUTES: u64 = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env_value("AGGREGATION_DEADLINE_MINUTES")
+            .map(|minutes| minutes.parse().expect("Invalid AGGREGATION_DEADLINE_MINUTES"))
+            .unwrap_or(60)
+    };
+    // Blocks a transaction may stay unmined before it is replaced with bumped fees
+    pub static ref REPLACEMENT_DEADLINE_BLOCKS: u64 = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env_value("REPLACEMENT_DEADLINE_BLOCKS")
+            .map(|blocks| blocks.parse().expect("Invalid REPLACEMENT_DEADLINE_BLOCKS"))
+            .unwrap_or(10)
+    };
+    // Leaf encoding for new deployments, V1 (domain separated) by default
+    pub static ref LEAF_VERSION: LeafVersion = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("LEAF_VERSION")
+            .map(|version| version.parse().expect("Invalid LEAF_VERSION"))
+            .unwrap_or(LeafVersion::V1)
+    };
+    // Tree hash for new deployments, keccak makes roots checkable with OpenZeppelin's MerkleProof
+    pub static ref TREE_HASH: TreeHash = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("TREE_HASH")
+            .map(|tree_hash| tree_hash.parse().expect("Invalid TREE_HASH"))
+            .unwrap_or(TreeHash::Sha256)
+    };
+    // Commit a digest of the public values in the leaves instead of the values, off by default
+    pub static ref HASHED_PUBLIC_VALUES: bool = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("HASHED_PUBLIC_VALUES")
+            .map(|hashed| hashed.parse().expect("Invalid HASHED_PUBLIC_VALUES"))
+            .unwrap_or(false)
+    };
+    // Pad epoch trees to this many levels so paths have a fixed length, unpadded by default
+    pub static ref TREE_DEPTH: Option<u8> = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("TREE_DEPTH")
+            .ok()
+            .map(|depth| depth.parse::<u8>().expect("Invalid TREE_DEPTH"))
+            .filter(|depth| *depth != 0)
+            .inspect(|depth| {
+                assert!(*depth <= MAX_PADDED_DEPTH, "TREE_DEPTH is over {}", MAX_PADDED_DEPTH)
+            })
+    };
+    // Verify the previous epoch's aggregate inside each new one, off by default
+    pub static ref RECURSIVE_CHAINING: bool = {
+        let env_content = include_str!("../../.env");
+        from_read(Cursor::new(env_content)).expect("Failed to parse .env content");
+        env::var("RECURSIVE_CHAINING")
+            .map(|chaining| chaining.parse().expect("Invalid RECURSIVE_CHAINING"))
+            .unwrap_or(false)
+    };
+}
+
+// A setting from `.env`, where an empty value means it is unset
+fn env_value(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
 }
 
 const HTTP_SERVER_ADDRESS: &str = "http_server:distro:sys";
@@ -59,12 +180,29 @@ fn setup_timer() {
     );
 }
 
+// Give up on the aggregation of an epoch if nothing came back by the deadline
+fn setup_aggregation_deadline(epoch: u64) {
+    set_timer(
+        *AGGREGATION_DEADLINE_MINUTES * 60000,
+        Some(serde_json::to_vec(&TimerType::AggregationDeadline(epoch)).unwrap()),
+    );
+}
+
+// Check on the receipts of our transactions every 15 seconds
+fn setup_receipt_timer() {
+    set_timer(
+        15000,
+        Some(serde_json::to_vec(&TimerType::PollReceipts).unwrap()),
+    );
+}
+
 fn handle_timer(
     _our: &Address,
     context: Option<Vec<u8>>,
     channel_id: &mut Option<u32>,
     _http_server: &mut HttpServer,
     state: &mut State,
+    eth_caller: &mut Option<ContractCaller>,
 ) -> anyhow::Result<()> {
     match context {
         None => Ok(()),
@@ -72,46 +210,163 @@ fn handle_timer(
             let timer_message: TimerType = serde_json::from_slice(&context)?;
             match timer_message {
                 TimerType::AggregateProofs => {
+                    setup_timer();
                     let Some(channel_id) = channel_id else {
                         kiprintln!("No channel id");
                         return Ok(());
                     };
-                    // Send aggregate proofs from state
-                    let proofs: Vec<AggregationInput> = state
-                        .get_all_kinode_ids()
-                        .iter()
-                        .filter_map(|kinode_id| state.get_proofs_for_kinode(kinode_id))
-                        .cloned()
-                        .collect();
-                    let serialized_proofs = serde_json::to_vec(&proofs)?;
-                    send_ws_push(
-                        *channel_id,
-                        WsMessageType::Binary,
-                        LazyLoadBlob {
-                            mime: None,
-                            bytes: serialized_proofs,
-                        },
-                    );
-
-                    // Go to the next epoch in the state
-                    state.epoch_next();
+                    // Nothing to aggregate, keep the epoch open
+                    if state.get_all_kinode_ids().is_empty() {
+                        return Ok(());
+                    }
+                    send_aggregation_job(*channel_id, state, false)?;
+                    Ok(())
+                }
+                TimerType::PollReceipts => {
+                    setup_receipt_timer();
+                    match eth_caller.as_ref() {
+                        Some(caller) => state.poll_receipts(
+                            caller,
+                            *CONFIRMATIONS,
+                            *REPLACEMENT_DEADLINE_BLOCKS,
+                        ),
+                        None => Ok(()),
+                    }
+                }
+                TimerType::AggregationDeadline(epoch) => {
+                    // A prover that hangs would hold back every later epoch
+                    if state.aggregating_epoch == Some(epoch) {
+                        state.fail_aggregation(
+                            epoch,
+                            format!(
+                                "No aggregate came back within {} minutes",
+                                *AGGREGATION_DEADLINE_MINUTES
+                            ),
+                        );
+                    }
                     Ok(())
                 }
             }
         }
     }
 }
+// Scheme of the `.env` settings, used until the first aggregate pins one
+fn configured_scheme() -> Scheme {
+    Scheme::new(*LEAF_VERSION, *TREE_HASH)
+        .with_hashed_public_values(*HASHED_PUBLIC_VALUES)
+        .with_padded_depth(*TREE_DEPTH)
+}
+
+// Send the current epoch's proofs to the extension for aggregation and move on to the next
+// epoch, or only have them executed for a cycle count with `dry_run`. Returns whether the
+// job was sent.
+fn send_aggregation_job(channel_id: u32, state: &mut State, dry_run: bool) -> anyhow::Result<bool> {
+    // A proof of another program would only revert on chain after we paid for it
+    if !dry_run {
+        if let Some(reason) = state.program_vkey_mismatch() {
+            kiprintln!("Not aggregating epoch {}: {}", state.current_epoch, reason);
+            return Ok(false);
+        }
+        // This epoch extends the previous one, which is only known once its aggregate is back
+        if let Some(epoch) = state.aggregating_epoch {
+            kiprintln!(
+                "Not aggregating epoch {}: epoch {} is still being aggregated",
+                state.current_epoch,
+                epoch
+            );
+            return Ok(false);
+        }
+    }
+    let prev_epoch = state.latest_epoch();
+    let prev_root = state.latest_root();
+    let accumulator = state.latest_accumulator();
+    let prev_aggregate = if *RECURSIVE_CHAINING {
+        state.chainable_aggregate().cloned()
+    } else {
+        None
+    };
+    let prev_chain = prev_aggregate
+        .as_ref()
+        .and_then(|proof| ContractCaller::decode_public_values(proof.public_values.as_slice()).ok())
+        .map(|values| ChainLink::from(&values));
+    // The accumulator can only be extended with the scheme it was started with
+    let configured = configured_scheme();
+    let scheme = state.latest_scheme().unwrap_or(configured);
+    if scheme != configured {
+        kiprintln!(
+            "Keeping scheme {:?} of the existing aggregates instead of {:?}",
+            scheme,
+            configured
+        );
+    }
+    // A dry run leaves the epoch as it is, so it can still be aggregated on another root
+    let inputs = state.manifest_inputs(scheme)?;
+    if !dry_run {
+        state.freeze_manifest(
+            prev_epoch,
+            prev_root,
+            accumulator.clone(),
+            prev_chain,
+            scheme,
+            &inputs,
+        )?;
+    }
+    let job = AggregationJob {
+        epoch: state.current_epoch,
+        inputs: inputs.into_iter().map(|(_, input)| input).collect(),
+        proof_mode: *AGGREGATION_PROOF_MODE,
+        prev_root,
+        accumulator,
+        scheme,
+        chain: *RECURSIVE_CHAINING,
+        prev_aggregate,
+        dry_run,
+    };
+    let serialized_job = serde_json::to_vec(&job)?;
+    send_ws_push(
+        channel_id,
+        WsMessageType::Binary,
+        LazyLoadBlob {
+            mime: None,
+            bytes: serialized_job,
+        },
+    );
+    // Proofs that arrive while this epoch is aggregated go into the next one
+    if !dry_run {
+        state.aggregating_epoch = Some(state.current_epoch);
+        setup_aggregation_deadline(state.current_epoch);
+        state.epoch_next(scheme);
+    }
+    Ok(true)
+}
+
 // From the terminal
 fn send_to_chain(
     output: AggregationOutput,
     eth_caller: &mut Option<ContractCaller>,
+    state: &mut State,
 ) -> anyhow::Result<()> {
-    if let Some(caller) = eth_caller.as_ref() {
-        caller.verify_aggregate_proof_and_update_root(output)?;
-        Ok(())
-    } else {
-        Err(anyhow::anyhow!("eth_caller is None"))
+    let Some(caller) = eth_caller.as_ref() else {
+        return Err(anyhow::anyhow!("eth_caller is None"));
+    };
+    if let Some((nonce, pending)) = state.nonces.pending_for_epoch(output.epoch) {
+        return Err(anyhow::anyhow!(
+            "Epoch {} is already in transaction 0x{} with nonce {}",
+            output.epoch,
+            hex::encode(pending.tx_hash),
+            nonce
+        ));
+    }
+    // After a failed send, or if the chain was unreachable at startup
+    if state.nonces.next_nonce().is_none() {
+        state.resync_nonces(&caller.caller)?;
     }
+    let epoch = output.epoch;
+    let result = caller.verify_aggregate_proof_and_update_root(output, &mut state.nonces);
+    state.save()?;
+    let (tx_hash, nonce) = result?;
+    state.set_submitted(epoch, tx_hash.into(), nonce);
+    Ok(())
 }
 
 fn handle_http_server_request(
@@ -120,7 +375,7 @@ fn handle_http_server_request(
     _http_server: &mut HttpServer,
     channel_id: &mut Option<u32>,
     state: &mut State,
-    _eth_caller: &mut Option<ContractCaller>,
+    eth_caller: &mut Option<ContractCaller>,
 ) -> anyhow::Result<()> {
     let server_request: HttpServerRequest = serde_json::from_slice(body)?;
     match server_request {
@@ -133,6 +388,15 @@ fn handle_http_server_request(
         }
         HttpServerRequest::WebSocketClose { .. } => {
             *channel_id = None;
+            state.worker_program_vkey = None;
+            state.worker_self_vkey = None;
+            // The extension can no longer send back the epoch it was aggregating
+            if let Some(epoch) = state.aggregating_epoch {
+                state.fail_aggregation(
+                    epoch,
+                    "Extension disconnected before the aggregate came back".to_string(),
+                );
+            }
         }
         // Should probably have a type for this
         HttpServerRequest::WebSocketPush { .. } => {
@@ -148,11 +412,112 @@ fn handle_http_server_request(
 
             match serde_json::from_slice::<WsMessage>(blob.bytes()) {
                 Ok(WsMessage::Aggregation(output)) => {
-                    // send_to_chain(output.clone(), eth_caller)?;
+                    // Its proofs are already in a later epoch
+                    if state.is_requeued(output.epoch) {
+                        kiprintln!(
+                            "Ignoring aggregation for epoch {}, its proofs were requeued",
+                            output.epoch
+                        );
+                        return Ok(());
+                    }
+                    state.finish_aggregation(output.epoch);
+                    // Don't trust the worker: the aggregate must commit the root of the leaves we sent
+                    // and the program vk the contract pins
+                    let expected = state
+                        .contract_self_vkey
+                        .and_then(|self_vkey| {
+                            state.expected_public_values(output.epoch, &output.rejected, self_vkey)
+                        })
+                        .map(|values| AggregationPublicValues::abi_encode(&values));
+                    if expected.as_deref() != Some(output.proof.public_values.as_slice()) {
+                        kiprintln!(
+                            "Rejecting aggregation for epoch {}: committed values 0x{} do not match the manifest",
+                            output.epoch,
+                            hex::encode(output.proof.public_values.as_slice())
+                        );
+                        state.fail_aggregation(
+                            output.epoch,
+                            "Aggregated public values do not match the leaf manifest".to_string(),
+                        );
+                        return Ok(());
+                    }
+                    // send_to_chain(output.clone(), eth_caller, state)?;
                     kiprintln!("Sent to chain");
-                    kiprintln!("Setting aggregated proof: {:?}", output.proof);
-                    state.set_aggregated_proof(output.proof.clone());
-                    state.epoch_next();
+                    kiprintln!(
+                        "Setting aggregated proof for epoch {}: {:?}",
+                        output.epoch,
+                        output.proof
+                    );
+                    if !output.rejected.is_empty() {
+                        kiprintln!(
+                            "{} submissions rejected in epoch {}",
+                            output.rejected.len(),
+                            output.epoch
+                        );
+                    }
+                    state.reject_submissions(output.epoch, &output.rejected);
+                    // Keep the compressed aggregate only if it proves the same public values
+                    let compressed = output.compressed.filter(|compressed| {
+                        compressed.public_values.as_slice() == output.proof.public_values.as_slice()
+                    });
+                    if let Some(cycles) = output.cycles {
+                        state.set_cycles(output.epoch, cycles);
+                    }
+                    state.set_aggregated_proof(output.epoch, output.proof);
+                    state.set_compressed_aggregate(output.epoch, compressed);
+                }
+                Ok(WsMessage::AggregationFailure(failure)) => {
+                    kiprintln!(
+                        "Aggregation failed for epoch {:?}: {}",
+                        failure.epoch,
+                        failure.reason
+                    );
+                    match (failure.epoch, state.aggregating_epoch) {
+                        (Some(epoch), Some(aggregating)) if epoch == aggregating => {
+                            state.reject_submissions(epoch, &failure.rejected);
+                            state.fail_aggregation(epoch, failure.reason);
+                        }
+                        // The extension could not read a job, which may be the one being
+                        // aggregated, and its result would never come back
+                        (None, Some(aggregating)) => {
+                            state.fail_aggregation(aggregating, failure.reason);
+                        }
+                        // A failed dry run leaves the open epoch as it is
+                        _ => {}
+                    }
+                }
+                Ok(WsMessage::Handshake(handshake)) => {
+                    kiprintln!(
+                        "Extension connected with program vkey {} on the {} backend",
+                        handshake.program_vkey,
+                        handshake.backend
+                    );
+                    state.worker_program_vkey = decode_vkey(&handshake.program_vkey);
+                    state.worker_self_vkey = decode_vkey(&handshake.self_vkey);
+                    // Retry the contract in case it was unreachable at startup
+                    if state.contract_program_vkey.is_none() {
+                        state.contract_program_vkey = eth_caller
+                            .as_ref()
+                            .and_then(|caller| caller.program_vkey().ok());
+                    }
+                    if state.contract_self_vkey.is_none() {
+                        state.contract_self_vkey = eth_caller
+                            .as_ref()
+                            .and_then(|caller| caller.self_vkey().ok());
+                    }
+                    if let Some(reason) = state.program_vkey_mismatch() {
+                        kiprintln!("Aggregation is paused: {}", reason);
+                    }
+                }
+                Ok(WsMessage::DryRun(report)) => {
+                    kiprintln!(
+                        "Dry run of epoch {}: {} leaves in {} cycles, {} submissions rejected",
+                        report.epoch,
+                        report.leaf_count,
+                        report.cycles,
+                        report.rejected.len()
+                    );
+                    state.set_cycles(report.epoch, report.cycles);
                 }
                 // Had to insert the dummy votes into the state on the WS client side
                 // since I couldn't get proof objects to load from the vfs
@@ -165,8 +530,9 @@ fn handle_http_server_request(
                             .insert(state.current_epoch, Default::default());
                     }
                     // Add each proof to the current epoch
+                    let scheme = state.latest_scheme().unwrap_or(configured_scheme());
                     for proof in dummy.proofs {
-                        state.add_proof("fake.dev".to_string(), proof);
+                        state.add_proof("fake.dev".to_string(), proof, scheme);
                     }
                 }
                 Err(e) => {
@@ -182,6 +548,13 @@ fn handle_http_server_request(
     }
     Ok(())
 }
+// A vkey the extension reported as 0x prefixed hex
+fn decode_vkey(vkey: &str) -> Option<[u8; 32]> {
+    hex::decode(vkey.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+}
+
 // Had to insert the dummy votes into the state on the WS client side
 // since I couldn't get proof objects to load from the vfs
 fn handle_insert_dummy_proofs(
@@ -223,7 +596,8 @@ fn handle_kinode_messages(
                 source,
                 aggregation_input,
             } => {
-                state.add_proof(source, aggregation_input);
+                let scheme = state.latest_scheme().unwrap_or(configured_scheme());
+                state.add_proof(source, aggregation_input, scheme);
                 Ok(())
             }
         }
@@ -271,6 +645,88 @@ fn handle_terminal_debug(
                 }
             }
         }
+        cmd if cmd.starts_with("inclusion_proof:") => {
+            let mut parts = cmd.split(':').skip(1);
+            if let (Some(Ok(epoch)), Some(kinode_id)) =
+                (parts.next().map(|epoch| epoch.parse::<u64>()), parts.next())
+            {
+                let accumulator = state.latest_accumulator();
+                match state.accumulator_proof(epoch, kinode_id) {
+                    Some((scheme, proof)) => kiprintln!(
+                        "Proof against accumulator root 0x{} ({:?}): {:?}",
+                        hex::encode(accumulator.root(scheme)),
+                        scheme,
+                        proof
+                    ),
+                    None => kiprintln!(
+                        "No aggregated submission from {} in epoch {}",
+                        kinode_id,
+                        epoch
+                    ),
+                }
+            }
+        }
+        cmd if cmd.starts_with("merkle_proof:") => {
+            let mut parts = cmd.split(':').skip(1);
+            if let (Some(Ok(epoch)), Some(kinode_id)) =
+                (parts.next().map(|epoch| epoch.parse::<u64>()), parts.next())
+            {
+                match state.inclusion_proof(epoch, kinode_id) {
+                    Some((scheme, leaf, root, proof)) => {
+                        // Sibling order is what OpenZeppelin's MerkleProof.verify expects
+                        let siblings: Vec<String> = proof
+                            .siblings
+                            .iter()
+                            .map(|sibling| format!("0x{}", hex::encode(sibling)))
+                            .collect();
+                        kiprintln!(
+                            "Leaf 0x{} in root 0x{} ({:?}), proof: [{}]",
+                            hex::encode(leaf),
+                            hex::encode(root),
+                            scheme,
+                            siblings.join(",")
+                        );
+                        // The contract computes the leaf from its preimage
+                        match state.included_entry(epoch, kinode_id) {
+                            Some(entry) if scheme.tree_hash == TreeHash::Keccak => {
+                                let submitter = entry.submitter.unwrap_or_default();
+                                let public_values = scheme.committed_public_values(
+                                    &entry.public_values,
+                                    entry.salt.as_ref(),
+                                );
+                                kiprintln!(
+                                    "verifyInclusion([{}], 0x{}, 0x{}, 0x{}, {})",
+                                    siblings.join(","),
+                                    hex::encode(words_to_bytes_le(&entry.vkey)),
+                                    hex::encode(public_values),
+                                    hex::encode(submitter.id_hash),
+                                    submitter.nonce
+                                );
+                            }
+                            _ => {
+                                kiprintln!("Only keccak trees can be checked with verifyInclusion")
+                            }
+                        }
+                    }
+                    None => kiprintln!(
+                        "No aggregated submission from {} in epoch {}",
+                        kinode_id,
+                        epoch
+                    ),
+                }
+            }
+        }
+        "pending_transactions" => {
+            kiprintln!("Next nonce: {:?}", state.nonces.next_nonce());
+            for (nonce, pending) in state.nonces.pending() {
+                kiprintln!(
+                    "Nonce {}: 0x{} for epoch {}",
+                    nonce,
+                    hex::encode(pending.tx_hash),
+                    pending.epoch
+                );
+            }
+        }
         "insert_dummy_proofs" => {
             handle_insert_dummy_proofs(state, our, channel_id)?;
         }
@@ -279,32 +735,28 @@ fn handle_terminal_debug(
                 kiprintln!("No channel id");
                 return Ok(());
             };
-            // Send aggregate proofs from state
-            let proofs: Vec<AggregationInput> = state
-                .get_all_kinode_ids()
-                .iter()
-                .filter_map(|kinode_id| state.get_proofs_for_kinode(kinode_id))
-                .cloned()
-                .collect();
-            let serialized_proofs = serde_json::to_vec(&proofs)?;
-            send_ws_push(
-                *channel_id,
-                WsMessageType::Binary,
-                LazyLoadBlob {
-                    mime: None,
-                    bytes: serialized_proofs,
-                },
-            );
+            send_aggregation_job(*channel_id, state, false)?;
+        }
+        "dry_run_aggregation" => {
+            let Some(channel_id) = channel_id else {
+                kiprintln!("No channel id");
+                return Ok(());
+            };
+            send_aggregation_job(*channel_id, state, true)?;
         }
         "send_to_chain" => {
-            let Some(proof) = state.get_aggregated_proof() else {
-                kiprintln!("No aggregated proof");
+            let Some((epoch, proof)) = state.next_unsubmitted_aggregate() else {
+                kiprintln!("No aggregated proof waiting to be sent");
                 return Ok(());
             };
             let output = AggregationOutput {
+                epoch,
                 proof: proof.clone(),
+                rejected: Vec::new(),
+                compressed: None,
+                cycles: None,
             };
-            send_to_chain(output, eth_caller)?;
+            send_to_chain(output, eth_caller, state)?;
         }
         _ => {
             kiprintln!("Unknown command: {}", command);
@@ -326,7 +778,7 @@ fn handle_message(
         Message::Response {
             source, context, ..
         } if source.process.to_string().as_str() == TIMER_ADDRESS => {
-            handle_timer(our, context, channel_id, http_server, state)
+            handle_timer(our, context, channel_id, http_server, state, eth_caller)
         }
         Message::Request { source, body, .. } => match source.process.to_string().as_str() {
             HTTP_SERVER_ADDRESS => {
@@ -356,13 +808,31 @@ fn init(our: Address) {
     kiprintln!("Initializing zkp-aggregator");
     info!("begin");
     setup_timer();
+    setup_receipt_timer();
 
     let mut state: State = get_typed_state(|bytes| State::load(bytes)).unwrap_or_default();
 
     let mut eth_caller: Option<ContractCaller> = Some(ContractCaller {
         caller: Caller::new(*CURRENT_CHAIN_ID, &WALLET_PRIVATE_KEY).unwrap(),
         contract_address: SP1_AGGREGATE_VERIFIER_CONTRACT_ADDRESS.to_string(),
+        supported_proof_modes: VERIFIER_PROOF_MODES.clone(),
+        fee_policy: *FEE_POLICY,
     });
+    // Aggregation waits for the extension to report the same program vkey
+    if let Some(caller) = eth_caller.as_ref() {
+        match caller.program_vkey() {
+            Ok(program_vkey) => state.contract_program_vkey = Some(program_vkey),
+            Err(e) => kiprintln!("Could not read PROGRAM_VKEY: {:?}", e),
+        }
+        match caller.self_vkey() {
+            Ok(self_vkey) => state.contract_self_vkey = Some(self_vkey),
+            Err(e) => kiprintln!("Could not read SELF_VKEY: {:?}", e),
+        }
+        // Pending transactions may have landed or been dropped while we were down
+        if let Err(e) = state.resync_nonces(&caller.caller) {
+            kiprintln!("Could not sync nonces with the chain: {:?}", e);
+        }
+    }
 
     let mut channel_id: Option<u32> = None;
     let mut http_server = HttpServer::new(5);
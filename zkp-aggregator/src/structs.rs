@@ -1,7 +1,14 @@
-use kinode_process_lib::{logging::error, set_state};
+use crate::caller::{Caller, NonceManager, TxReceipt};
+use crate::contract_caller::ContractCaller;
+use kinode_process_lib::{eth::BlockId, kiprintln, logging::error, set_state};
 use serde::{Deserialize, Serialize};
-use shared_types::AggregationInput;
-use sp1_sdk::SP1ProofWithPublicValues;
+use shared_merkle::{
+    accumulator::{accumulator_proof, Accumulator, AccumulatorProof},
+    public_values::{chain_digest, AggregationPublicValues},
+    tree_inclusion_proof, tree_root, Hash, InclusionProof, Scheme, Submitter,
+};
+use shared_types::{AggregationInput, RejectedInput};
+use sp1_sdk::{HashableKey, SP1ProofWithPublicValues};
 use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug)]
@@ -17,6 +24,44 @@ pub type KinodeId = String;
 #[derive(Serialize, Deserialize)]
 pub enum TimerType {
     AggregateProofs,
+    PollReceipts,
+    // An epoch sent for aggregation ran out of time
+    AggregationDeadline(u64),
+}
+
+// Where an epoch's aggregate is on chain
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub enum EpochStatus {
+    // Not sent to the contract
+    #[default]
+    Unsubmitted,
+    // Sent, waiting to be mined and confirmed
+    Submitted,
+    Confirmed,
+    Failed(String),
+}
+
+// Transaction that carries an epoch's aggregate, with its receipt once mined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Submission {
+    // Latest transaction, or the one that was mined
+    pub tx_hash: [u8; 32],
+    pub nonce: u64,
+    pub receipt: Option<TxReceipt>,
+    // Other transactions with the same nonce, sent with lower fees or replaced later
+    #[serde(default)]
+    pub replaced: Vec<[u8; 32]>,
+}
+
+impl Submission {
+    // Make `tx_hash` the current transaction and keep the other one in `replaced`
+    fn switch_to(&mut self, tx_hash: [u8; 32]) {
+        if self.tx_hash != tx_hash {
+            self.replaced.retain(|replaced| *replaced != tx_hash);
+            self.replaced.push(self.tx_hash);
+            self.tx_hash = tx_hash;
+        }
+    }
 }
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ProofSubmissionRequest {
@@ -24,10 +69,172 @@ pub struct ProofSubmissionRequest {
     pub aggregation_input: AggregationInput,
 }
 
+// A leaf as it was sent for aggregation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub kinode_id: KinodeId,
+    pub vkey: [u32; 8],
+    pub public_values: Vec<u8>,
+    // Hash of the kinode id and the submission nonce, entries from before it was tracked have none
+    #[serde(default)]
+    pub submitter: Option<Submitter>,
+    // Salt the submitter blinds its public values digest with
+    #[serde(default)]
+    pub salt: Option<[u8; 32]>,
+}
+
+impl ManifestEntry {
+    pub fn leaf(&self, scheme: Scheme) -> Hash {
+        scheme.leaf_hash(
+            &self.vkey,
+            &scheme.committed_public_values(&self.public_values, self.salt.as_ref()),
+            &self.submitter.unwrap_or_default(),
+        )
+    }
+}
+
+// Chain state of the previous aggregate an epoch extends
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainLink {
+    pub self_vkey: [u8; 32],
+    pub chain_digest: [u8; 32],
+    pub chain_start_epoch: u64,
+}
+
+impl From<&AggregationPublicValues> for ChainLink {
+    fn from(values: &AggregationPublicValues) -> Self {
+        Self {
+            self_vkey: values.selfVkey.into(),
+            chain_digest: values.chainDigest.into(),
+            chain_start_epoch: values.chainStartEpoch,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct EpochState {
     proofs_by_kinode_id: HashMap<KinodeId, AggregationInput>,
     current_aggregated_proof: Option<SP1ProofWithPublicValues>,
+    #[serde(default)]
+    aggregation_error: Option<String>,
+    // Leaves in the order they were sent for aggregation, frozen at dispatch
+    #[serde(default)]
+    manifest: Vec<ManifestEntry>,
+    // Submissions the extension excluded from the aggregate, with the reason
+    #[serde(default)]
+    rejected: HashMap<KinodeId, String>,
+    // Epoch whose aggregate this one extends, none for the first one
+    #[serde(default)]
+    prev_epoch: Option<u64>,
+    // Root the aggregate of this epoch replaces
+    #[serde(default)]
+    prev_root: [u8; 32],
+    // Accumulator over every earlier epoch, which this epoch's leaves are appended to
+    #[serde(default)]
+    prev_accumulator: Accumulator,
+    // Previous aggregate this epoch's aggregate verifies, if it was sent along
+    #[serde(default)]
+    prev_chain: Option<ChainLink>,
+    // How the leaves of this epoch are hashed, epochs from before versioning used V0 and sha256
+    #[serde(flatten)]
+    scheme: Scheme,
+    // Nodes whose submission makes the same leaf as an earlier node's, mapped to the node
+    // whose submission holds the leaf
+    #[serde(default)]
+    duplicates: HashMap<KinodeId, KinodeId>,
+    // Proofs of the duplicates, requeued to take the leaf if the proof holding it is rejected:
+    // proofs are only verified by the extension, so the first one may be bogus
+    #[serde(default)]
+    fallbacks: HashMap<KinodeId, AggregationInput>,
+    // Cycles the aggregator program took to execute the batch, from a dry run or the aggregation
+    #[serde(default)]
+    cycles: Option<u64>,
+    #[serde(default)]
+    status: EpochStatus,
+    #[serde(default)]
+    submission: Option<Submission>,
+    // Whether the proofs of this epoch went back into the queue after it failed, a late
+    // aggregate of it is ignored
+    #[serde(default)]
+    requeued: bool,
+}
+
+impl EpochState {
+    // Manifest entries that made it into the aggregate, in leaf order
+    fn included_entries(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.manifest
+            .iter()
+            .filter(|entry| !self.rejected.contains_key(&entry.kinode_id))
+    }
+
+    fn included_leaves(&self) -> Vec<Hash> {
+        self.included_entries()
+            .map(|entry| entry.leaf(self.scheme))
+            .collect()
+    }
+
+    // Position of a node's leaf among the included entries, following duplicates to their leaf
+    fn included_position(&self, kinode_id: &str) -> Option<usize> {
+        let owner = self
+            .duplicates
+            .get(kinode_id)
+            .map(String::as_str)
+            .unwrap_or(kinode_id);
+        self.included_entries()
+            .position(|entry| entry.kinode_id == owner)
+    }
+
+    // Node whose submission already makes the leaf `input` would make under `scheme`. Leaves
+    // that bind their submitter never repeat across nodes.
+    fn submission_of(&self, input: &AggregationInput, scheme: Scheme) -> Option<&KinodeId> {
+        if scheme.leaf_version.binds_submitter() {
+            return None;
+        }
+        let leaf = |input: &AggregationInput| {
+            scheme.leaf_hash(
+                &input.vk.hash_u32(),
+                &scheme.committed_public_values(
+                    input.proof.public_values.as_slice(),
+                    input.salt.as_ref(),
+                ),
+                &Submitter::default(),
+            )
+        };
+        let new_leaf = leaf(input);
+        self.proofs_by_kinode_id
+            .iter()
+            .find(|(_, existing)| leaf(existing) == new_leaf)
+            .map(|(kinode_id, _)| kinode_id)
+    }
+
+    // Whether a node already submitted to this epoch, as a leaf or as a duplicate of one
+    fn has_submission(&self, kinode_id: &str) -> bool {
+        self.proofs_by_kinode_id.contains_key(kinode_id) || self.duplicates.contains_key(kinode_id)
+    }
+
+    // Add a node's proof, or record it as a duplicate when another node's proof already
+    // makes its leaf. Returns whether it got a leaf of its own.
+    fn insert_proof(
+        &mut self,
+        kinode_id: KinodeId,
+        proof: AggregationInput,
+        scheme: Scheme,
+    ) -> bool {
+        if self.has_submission(&kinode_id) {
+            return false;
+        }
+        match self.submission_of(&proof, scheme).cloned() {
+            Some(existing) => {
+                self.duplicates.insert(kinode_id.clone(), existing);
+                self.fallbacks.insert(kinode_id, proof);
+                false
+            }
+            None => {
+                self.proofs_by_kinode_id.insert(kinode_id, proof);
+                true
+            }
+        }
+    }
 }
 
 impl Default for EpochState {
@@ -35,6 +242,20 @@ impl Default for EpochState {
         Self {
             proofs_by_kinode_id: HashMap::new(),
             current_aggregated_proof: None,
+            aggregation_error: None,
+            manifest: Vec::new(),
+            rejected: HashMap::new(),
+            prev_epoch: None,
+            prev_root: [0u8; 32],
+            prev_accumulator: Accumulator::default(),
+            prev_chain: None,
+            scheme: Scheme::default(),
+            duplicates: HashMap::new(),
+            fallbacks: HashMap::new(),
+            cycles: None,
+            status: EpochStatus::Unsubmitted,
+            submission: None,
+            requeued: false,
         }
     }
 }
@@ -43,6 +264,34 @@ impl Default for EpochState {
 pub struct State {
     pub current_epoch: u64,
     pub epoch_history: BTreeMap<u64, EpochState>,
+    // Compressed aggregate of the latest chained epoch, verified by the next aggregate
+    #[serde(default)]
+    pub last_compressed_aggregate: Option<(u64, SP1ProofWithPublicValues)>,
+    // Next submission nonce of every node, bound into its leaves
+    #[serde(default)]
+    pub submission_nonces: HashMap<KinodeId, u64>,
+    // Nonces and outstanding transactions of our wallet
+    #[serde(default)]
+    pub nonces: NonceManager,
+    // Proofs of failed epochs waiting to be aggregated again, oldest first. Each one goes into
+    // the first epoch its node has not submitted to.
+    #[serde(default)]
+    pub requeued: Vec<(KinodeId, AggregationInput)>,
+    // PROGRAM_VKEY of the verifier contract, read at startup
+    #[serde(skip)]
+    pub contract_program_vkey: Option<[u8; 32]>,
+    // SELF_VKEY of the verifier contract, the only `selfVkey` an aggregate may commit
+    #[serde(skip)]
+    pub contract_self_vkey: Option<[u8; 32]>,
+    // Program vkey the connected extension reported in its handshake
+    #[serde(skip)]
+    pub worker_program_vkey: Option<[u8; 32]>,
+    // Same vk as the extension's program commits it, if its handshake had it
+    #[serde(skip)]
+    pub worker_self_vkey: Option<[u8; 32]>,
+    // Epoch sent for aggregation whose result has not come back, the next one waits for it
+    #[serde(skip)]
+    pub aggregating_epoch: Option<u64>,
 }
 
 impl Default for State {
@@ -54,6 +303,15 @@ impl Default for State {
         Self {
             current_epoch: 0,
             epoch_history,
+            last_compressed_aggregate: None,
+            submission_nonces: HashMap::new(),
+            nonces: NonceManager::default(),
+            requeued: Vec::new(),
+            contract_program_vkey: None,
+            contract_self_vkey: None,
+            worker_program_vkey: None,
+            worker_self_vkey: None,
+            aggregating_epoch: None,
         }
     }
 }
@@ -72,12 +330,32 @@ impl State {
         }
     }
 
-    pub fn epoch_next(&mut self) {
+    // Why the extension's aggregates would revert on chain, if they would
+    pub fn program_vkey_mismatch(&self) -> Option<String> {
+        match (self.contract_program_vkey, self.worker_program_vkey) {
+            (None, _) => Some("PROGRAM_VKEY of the contract is unknown".to_string()),
+            (_, None) => Some("No extension has reported its program vkey".to_string()),
+            (Some(contract), Some(worker)) if contract != worker => Some(format!(
+                "Extension proves program 0x{}, the contract accepts 0x{}",
+                hex::encode(worker),
+                hex::encode(contract)
+            )),
+            _ => match (self.contract_self_vkey, self.worker_self_vkey) {
+                (None, _) => Some("SELF_VKEY of the contract is unknown".to_string()),
+                (Some(contract), Some(worker)) if contract != worker => Some(format!(
+                    "Extension's program commits self vkey 0x{}, the contract pins 0x{}",
+                    hex::encode(worker),
+                    hex::encode(contract)
+                )),
+                _ => None,
+            },
+        }
+    }
+
+    // Open the next epoch, which starts with the requeued proofs it has room for
+    pub fn epoch_next(&mut self, scheme: Scheme) {
         // Create new EpochState for the next epoch
-        let new_epoch_state = EpochState {
-            proofs_by_kinode_id: HashMap::new(),
-            current_aggregated_proof: None,
-        };
+        let new_epoch_state = EpochState::default();
 
         // Increment epoch
         self.current_epoch += 1;
@@ -85,21 +363,77 @@ impl State {
         // Store the new epoch state
         self.epoch_history
             .insert(self.current_epoch, new_epoch_state);
+        self.take_requeued(scheme);
     }
 
-    pub fn add_proof(&mut self, kinode_id: KinodeId, proof: AggregationInput) -> bool {
+    // A submission from another node that makes the same leaf under `scheme` shares the leaf
+    // of the first one
+    pub fn add_proof(
+        &mut self,
+        kinode_id: KinodeId,
+        proof: AggregationInput,
+        scheme: Scheme,
+    ) -> bool {
         let is_new = self
             .current_epoch_state_mut()
-            .map(|state| {
-                state.proofs_by_kinode_id.entry(kinode_id).or_insert(proof);
-                true
-            })
+            .map(|state| state.insert_proof(kinode_id, proof, scheme))
             .unwrap_or(false);
 
         self.save().unwrap_or_default();
         is_new
     }
 
+    // Move the requeued proofs whose node has not submitted to the current epoch into it
+    fn take_requeued(&mut self, scheme: Scheme) {
+        for (kinode_id, proof) in std::mem::take(&mut self.requeued) {
+            let has_room = self
+                .current_epoch_state()
+                .is_some_and(|state| !state.has_submission(&kinode_id));
+            match self.current_epoch_state_mut() {
+                Some(state) if has_room => {
+                    state.insert_proof(kinode_id, proof, scheme);
+                }
+                _ => self.requeued.push((kinode_id, proof)),
+            }
+        }
+    }
+
+    // Queue the proofs of a failed epoch again, except the ones the extension rejected, then
+    // the fallbacks of its duplicates so the leaves go to the same proofs again. An epoch is
+    // only requeued once.
+    fn requeue_epoch(&mut self, epoch: u64) {
+        let Some(state) = self.epoch_history.get_mut(&epoch) else {
+            return;
+        };
+        if state.requeued {
+            return;
+        }
+        state.requeued = true;
+        let mut proofs: Vec<(KinodeId, AggregationInput)> = state
+            .proofs_by_kinode_id
+            .iter()
+            .filter(|(kinode_id, _)| !state.rejected.contains_key(*kinode_id))
+            .map(|(kinode_id, proof)| (kinode_id.clone(), proof.clone()))
+            .collect();
+        proofs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut fallbacks: Vec<(KinodeId, AggregationInput)> =
+            std::mem::take(&mut state.fallbacks).into_iter().collect();
+        fallbacks.sort_by(|(a, _), (b, _)| a.cmp(b));
+        proofs.extend(fallbacks);
+        let scheme = state.scheme;
+        kiprintln!("Requeued {} proofs of epoch {}", proofs.len(), epoch);
+        self.requeued.extend(proofs);
+        let scheme = self.latest_scheme().unwrap_or(scheme);
+        self.take_requeued(scheme);
+    }
+
+    // Whether the proofs of an epoch were requeued, so its aggregate must not be used
+    pub fn is_requeued(&self, epoch: u64) -> bool {
+        self.epoch_history
+            .get(&epoch)
+            .is_some_and(|state| state.requeued)
+    }
+
     pub fn get_proofs_for_kinode(&self, kinode_id: &str) -> Option<&AggregationInput> {
         self.current_epoch_state()?
             .proofs_by_kinode_id
@@ -112,17 +446,496 @@ impl State {
             .unwrap_or_default()
     }
 
-    pub fn set_aggregated_proof(&mut self, proof: SP1ProofWithPublicValues) {
-        if let Some(state) = self.current_epoch_state_mut() {
-            state.current_aggregated_proof = Some(proof);
+    // The current epoch's proofs in manifest order, with the submitters they would be frozen
+    // with. Changes nothing, so dry runs build their jobs from it too.
+    pub fn manifest_inputs(
+        &self,
+        scheme: Scheme,
+    ) -> anyhow::Result<Vec<(KinodeId, AggregationInput)>> {
+        let epoch = self.current_epoch;
+        let Some(state) = self.epoch_history.get(&epoch) else {
+            return Err(anyhow::anyhow!("Epoch {} has no state", epoch));
+        };
+        if state.current_aggregated_proof.is_some() {
+            return Err(anyhow::anyhow!("Epoch {} is already aggregated", epoch));
+        }
+        let mut kinode_ids: Vec<KinodeId> = state.proofs_by_kinode_id.keys().cloned().collect();
+        kinode_ids.sort();
+        Ok(kinode_ids
+            .into_iter()
+            .filter_map(|kinode_id| {
+                let mut input = state.proofs_by_kinode_id.get(&kinode_id)?.clone();
+                let nonce = self.submission_nonces.get(&kinode_id).copied();
+                input.submitter = Some(scheme.submitter(&kinode_id, nonce.unwrap_or_default()));
+                Some((kinode_id, input))
+            })
+            .collect())
+    }
+
+    // Freeze the current epoch on top of the previous aggregate with the inputs from
+    // `manifest_inputs`, spending their submission nonces
+    pub fn freeze_manifest(
+        &mut self,
+        prev_epoch: Option<u64>,
+        prev_root: [u8; 32],
+        prev_accumulator: Accumulator,
+        prev_chain: Option<ChainLink>,
+        scheme: Scheme,
+        inputs: &[(KinodeId, AggregationInput)],
+    ) -> anyhow::Result<()> {
+        let epoch = self.current_epoch;
+        let Some(state) = self.epoch_history.get_mut(&epoch) else {
+            return Err(anyhow::anyhow!("Epoch {} has no state", epoch));
+        };
+        for (kinode_id, _) in inputs {
+            *self.submission_nonces.entry(kinode_id.clone()).or_default() += 1;
+        }
+        state.prev_epoch = prev_epoch;
+        state.prev_root = prev_root;
+        state.prev_accumulator = prev_accumulator;
+        state.prev_chain = prev_chain;
+        state.scheme = scheme;
+        state.manifest = inputs
+            .iter()
+            .map(|(kinode_id, input)| ManifestEntry {
+                kinode_id: kinode_id.clone(),
+                vkey: input.vk.hash_u32(),
+                public_values: input.proof.public_values.to_vec(),
+                submitter: input.submitter,
+                salt: input.salt,
+            })
+            .collect();
+        self.save().unwrap_or_default();
+        Ok(())
+    }
+
+    // Public values the aggregate of an epoch must commit, given the inputs the extension rejected.
+    // `self_vkey` is the contract's SELF_VKEY, never what the extension committed: the program
+    // takes it as an input.
+    pub fn expected_public_values(
+        &self,
+        epoch: u64,
+        rejected: &[RejectedInput],
+        self_vkey: [u8; 32],
+    ) -> Option<AggregationPublicValues> {
+        let state = self.epoch_history.get(&epoch)?;
+        let scheme = state.scheme;
+        let leaves: Vec<Hash> = state
+            .manifest
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !rejected.iter().any(|rejection| rejection.index == *index))
+            .map(|(_, entry)| entry.leaf(scheme))
+            .collect();
+        let root = tree_root(scheme, &leaves)?;
+        let mut accumulator = state.prev_accumulator.clone();
+        accumulator.extend(scheme, &leaves);
+        let accumulator_root = accumulator.root(scheme);
+        let (prev_chain_digest, chain_start_epoch) = match &state.prev_chain {
+            Some(link) => (link.chain_digest, link.chain_start_epoch),
+            None => ([0u8; 32], epoch),
+        };
+        Some(AggregationPublicValues {
+            epoch,
+            leafCount: leaves.len() as u64,
+            prevRoot: state.prev_root.into(),
+            newRoot: root.into(),
+            prevAccumulatorRoot: state.prev_accumulator.root(scheme).into(),
+            accumulatorRoot: accumulator_root.into(),
+            accumulatorLeafCount: accumulator.leaf_count,
+            selfVkey: self_vkey.into(),
+            chainDigest: chain_digest(&prev_chain_digest, epoch, &root, &accumulator_root).into(),
+            chainStartEpoch: chain_start_epoch,
+            leafVersion: scheme.leaf_version as u8,
+            treeHash: scheme.tree_hash as u8,
+            hashedPublicValues: scheme.hashed_public_values,
+            treeDepth: scheme.committed_depth(),
+        })
+    }
+
+    // Most recent epoch with an aggregate that can still land, the next epoch extends it
+    pub fn latest_epoch(&self) -> Option<u64> {
+        self.aggregated_epochs().last().map(|(epoch, _)| epoch)
+    }
+
+    // Root committed by the most recent aggregate, zero before the first one
+    pub fn latest_root(&self) -> [u8; 32] {
+        self.aggregated_epochs()
+            .last()
+            .and_then(|(_, state)| state.current_aggregated_proof.as_ref())
+            .and_then(|proof| {
+                ContractCaller::decode_public_values(proof.public_values.as_slice()).ok()
+            })
+            .map(|values| values.newRoot.into())
+            .unwrap_or_default()
+    }
+
+    // Accumulator after the most recent aggregate, empty before the first one
+    pub fn latest_accumulator(&self) -> Accumulator {
+        self.aggregated_epochs()
+            .last()
+            .map(|(_, state)| {
+                let mut accumulator = state.prev_accumulator.clone();
+                accumulator.extend(state.scheme, &state.included_leaves());
+                accumulator
+            })
+            .unwrap_or_default()
+    }
+
+    // Scheme of the most recent aggregate, which every later epoch has to keep
+    pub fn latest_scheme(&self) -> Option<Scheme> {
+        self.aggregated_epochs()
+            .last()
+            .map(|(_, state)| state.scheme)
+    }
+
+    // Proof that a submission is in the root of its epoch's aggregate, with its leaf and that root
+    pub fn inclusion_proof(
+        &self,
+        epoch: u64,
+        kinode_id: &str,
+    ) -> Option<(Scheme, Hash, Hash, InclusionProof)> {
+        let state = self.epoch_history.get(&epoch)?;
+        state.current_aggregated_proof.as_ref()?;
+        let leaves = state.included_leaves();
+        let index = state.included_position(kinode_id)?;
+        let root = tree_root(state.scheme, &leaves)?;
+        let proof = tree_inclusion_proof(state.scheme, &leaves, index)?;
+        Some((state.scheme, leaves[index], root, proof))
+    }
+
+    // Manifest entry whose leaf holds a node's submission in an epoch's aggregate
+    pub fn included_entry(&self, epoch: u64, kinode_id: &str) -> Option<&ManifestEntry> {
+        let state = self.epoch_history.get(&epoch)?;
+        let index = state.included_position(kinode_id)?;
+        state.included_entries().nth(index)
+    }
+
+    // Proof that a submission of an aggregated epoch is in the latest accumulator
+    pub fn accumulator_proof(
+        &self,
+        epoch: u64,
+        kinode_id: &str,
+    ) -> Option<(Scheme, AccumulatorProof)> {
+        let mut leaves = Vec::new();
+        let mut index = None;
+        for (aggregated_epoch, state) in self.aggregated_epochs() {
+            if aggregated_epoch == epoch {
+                index = state
+                    .included_position(kinode_id)
+                    .map(|position| (leaves.len() + position) as u64);
+            }
+            leaves.extend(state.included_leaves());
+        }
+        let scheme = self.latest_scheme()?;
+        accumulator_proof(scheme, &leaves, index?).map(|proof| (scheme, proof))
+    }
+
+    // Epochs with an aggregated proof that did not fail on chain, oldest first. Each one
+    // extends the one before it.
+    fn aggregated_epochs(&self) -> impl Iterator<Item = (u64, &EpochState)> {
+        self.epoch_history
+            .iter()
+            .filter(|(_, state)| {
+                state.current_aggregated_proof.is_some()
+                    && !matches!(state.status, EpochStatus::Failed(_))
+            })
+            .map(|(epoch, state)| (*epoch, state))
+    }
+
+    // Oldest aggregate that was not sent to the contract yet. Later ones extend it, so
+    // it has to land first.
+    pub fn next_unsubmitted_aggregate(&self) -> Option<(u64, &SP1ProofWithPublicValues)> {
+        self.aggregated_epochs()
+            .find(|(_, state)| state.status == EpochStatus::Unsubmitted)
+            .and_then(|(epoch, state)| {
+                state
+                    .current_aggregated_proof
+                    .as_ref()
+                    .map(|proof| (epoch, proof))
+            })
+    }
+
+    // The aggregation of `epoch` is over, whatever came back
+    pub fn finish_aggregation(&mut self, epoch: u64) {
+        if self.aggregating_epoch == Some(epoch) {
+            self.aggregating_epoch = None;
+        }
+    }
+
+    // Mark an epoch as failed on chain, along with the aggregates built on top of it: their
+    // previous root will never be on chain. The next epoch extends the last one still good,
+    // and the proofs of the failed ones are requeued to be aggregated on top of it.
+    fn fail_epoch(&mut self, epoch: u64, reason: String) {
+        let mut failed = vec![epoch];
+        if let Some(state) = self.epoch_history.get_mut(&epoch) {
+            kiprintln!("Epoch {} failed: {}", epoch, reason);
+            state.status = EpochStatus::Failed(reason);
+        }
+        for (later, state) in self.epoch_history.range_mut(epoch + 1..) {
+            let Some(prev_epoch) = state.prev_epoch.filter(|prev| failed.contains(prev)) else {
+                continue;
+            };
+            // One still aggregating is failed when its aggregate comes back
+            if state.current_aggregated_proof.is_none() {
+                continue;
+            }
+            if !matches!(state.status, EpochStatus::Failed(_)) {
+                state.status =
+                    EpochStatus::Failed(format!("Extends epoch {} which failed", prev_epoch));
+                kiprintln!("Epoch {} failed: {:?}", later, state.status);
+            }
+            failed.push(*later);
+        }
+        for epoch in failed {
+            self.requeue_epoch(epoch);
+        }
+    }
+
+    // Mark the submissions behind the rejected manifest positions as rejected, and requeue the
+    // fallbacks of their duplicates so one of them takes the leaf in the current epoch
+    pub fn reject_submissions(&mut self, epoch: u64, rejected: &[RejectedInput]) {
+        let Some(state) = self.epoch_history.get_mut(&epoch) else {
+            return;
+        };
+        for rejection in rejected {
+            match state.manifest.get(rejection.index) {
+                Some(entry) => {
+                    state
+                        .rejected
+                        .insert(entry.kinode_id.clone(), rejection.reason.clone());
+                }
+                None => error!(
+                    "Rejected input {} is not in the manifest of epoch {}",
+                    rejection.index, epoch
+                ),
+            }
+        }
+        let mut promoted: Vec<KinodeId> = state
+            .duplicates
+            .iter()
+            .filter(|(_, owner)| state.rejected.contains_key(*owner))
+            .map(|(kinode_id, _)| kinode_id.clone())
+            .collect();
+        promoted.sort();
+        let fallbacks: Vec<(KinodeId, AggregationInput)> = promoted
+            .into_iter()
+            .filter_map(|kinode_id| {
+                state.duplicates.remove(&kinode_id);
+                let proof = state.fallbacks.remove(&kinode_id)?;
+                Some((kinode_id, proof))
+            })
+            .collect();
+        if !fallbacks.is_empty() {
+            kiprintln!(
+                "Requeued {} proofs of epoch {} whose leaf was rejected",
+                fallbacks.len(),
+                epoch
+            );
+            let scheme = state.scheme;
+            self.requeued.extend(fallbacks);
+            let scheme = self.latest_scheme().unwrap_or(scheme);
+            self.take_requeued(scheme);
+        }
+        self.save().unwrap_or_default();
+    }
+
+    pub fn set_aggregated_proof(&mut self, epoch: u64, proof: SP1ProofWithPublicValues) {
+        let Some(state) = self.epoch_history.get_mut(&epoch) else {
+            return;
+        };
+        state.current_aggregated_proof = Some(proof);
+        state.aggregation_error = None;
+        // The epoch it extends may have failed while it was aggregated
+        let failed_prev = state.prev_epoch.filter(|prev_epoch| {
+            self.epoch_history
+                .get(prev_epoch)
+                .is_some_and(|prev| matches!(prev.status, EpochStatus::Failed(_)))
+        });
+        if let Some(prev_epoch) = failed_prev {
+            self.fail_epoch(epoch, format!("Extends epoch {} which failed", prev_epoch));
+        }
+        self.save().unwrap_or_default();
+    }
+
+    // Keep the compressed aggregate of an epoch for the next one to verify, or drop the
+    // chain when the epoch has none
+    pub fn set_compressed_aggregate(
+        &mut self,
+        epoch: u64,
+        compressed: Option<SP1ProofWithPublicValues>,
+    ) {
+        self.last_compressed_aggregate = compressed.map(|proof| (epoch, proof));
+        self.save().unwrap_or_default();
+    }
+
+    // Compressed aggregate to chain the next epoch to, only if it is the latest aggregate
+    pub fn chainable_aggregate(&self) -> Option<&SP1ProofWithPublicValues> {
+        let (epoch, proof) = self.last_compressed_aggregate.as_ref()?;
+        (Some(*epoch) == self.latest_epoch()).then_some(proof)
+    }
+
+    // Sync our nonces with the chain. Epochs whose transaction the chain dropped have failed,
+    // the ones that landed are settled by `poll_receipts`.
+    pub fn resync_nonces(&mut self, caller: &Caller) -> anyhow::Result<()> {
+        for (nonce, pending) in self.nonces.resync(caller)? {
+            self.fail_epoch(
+                pending.epoch,
+                format!(
+                    "Transaction 0x{} with nonce {} was dropped",
+                    hex::encode(pending.tx_hash),
+                    nonce
+                ),
+            );
+        }
+        self.save()
+    }
+
+    pub fn set_submitted(&mut self, epoch: u64, tx_hash: [u8; 32], nonce: u64) {
+        if let Some(state) = self.epoch_history.get_mut(&epoch) {
+            state.status = EpochStatus::Submitted;
+            state.submission = Some(Submission {
+                tx_hash,
+                nonce,
+                receipt: None,
+                replaced: Vec::new(),
+            });
             self.save().unwrap_or_default();
         }
     }
 
-    pub fn get_aggregated_proof(&self) -> Option<&SP1ProofWithPublicValues> {
-        self.current_epoch_state()?
-            .current_aggregated_proof
-            .as_ref()
+    // Record the receipts of outstanding transactions and settle their epochs once they are
+    // `confirmations` blocks deep, or failed when another transaction took their nonce.
+    // Transactions still unmined `deadline_blocks` after they were sent are replaced with
+    // bumped fees
+    pub fn poll_receipts(
+        &mut self,
+        caller: &ContractCaller,
+        confirmations: u64,
+        deadline_blocks: u64,
+    ) -> anyhow::Result<()> {
+        let pending: Vec<_> = self
+            .nonces
+            .pending()
+            .map(|(nonce, pending)| (*nonce, pending.clone()))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        // Read before the receipts, so a missing receipt below it cannot be one still to come
+        let mined = caller.caller.transaction_count(BlockId::latest())?;
+        let head = caller.caller.block_number()?;
+        for (nonce, pending) in pending {
+            // Any transaction in the replacement chain may be the one that landed
+            let mut landed = None;
+            for tx_hash in pending.hashes() {
+                if let Some(receipt) = caller.caller.receipt(*tx_hash)? {
+                    landed = Some((*tx_hash, receipt));
+                    break;
+                }
+            }
+            let Some(state) = self.epoch_history.get_mut(&pending.epoch) else {
+                self.nonces.complete(nonce);
+                continue;
+            };
+            match landed {
+                Some((tx_hash, receipt)) => {
+                    if let Some(submission) = state.submission.as_mut() {
+                        submission.switch_to(tx_hash);
+                        submission.receipt = Some(receipt);
+                    }
+                    if head + 1 < receipt.block_number + confirmations {
+                        continue;
+                    }
+                    self.nonces.complete(nonce);
+                    if receipt.success {
+                        state.status = EpochStatus::Confirmed;
+                        kiprintln!(
+                            "Epoch {} is confirmed in block {} with 0x{} ({} gas)",
+                            pending.epoch,
+                            receipt.block_number,
+                            hex::encode(tx_hash),
+                            receipt.gas_used
+                        );
+                    } else {
+                        self.fail_epoch(
+                            pending.epoch,
+                            format!(
+                                "Transaction 0x{} reverted in block {}",
+                                hex::encode(tx_hash),
+                                receipt.block_number
+                            ),
+                        );
+                    }
+                }
+                None if nonce < mined => {
+                    self.nonces.complete(nonce);
+                    self.fail_epoch(
+                        pending.epoch,
+                        format!(
+                            "Transaction 0x{} was dropped, nonce {} went to another one",
+                            hex::encode(pending.tx_hash),
+                            nonce
+                        ),
+                    );
+                }
+                None => match pending.sent_at_block {
+                    Some(sent_at_block) if head >= sent_at_block + deadline_blocks => {
+                        match caller.replace_stuck_tx(&mut self.nonces, nonce) {
+                            Ok(tx_hash) => {
+                                kiprintln!(
+                                    "Replaced stuck transaction 0x{} of epoch {} with 0x{}",
+                                    hex::encode(pending.tx_hash),
+                                    pending.epoch,
+                                    hex::encode(tx_hash)
+                                );
+                                if let Some(submission) = state.submission.as_mut() {
+                                    submission.switch_to(tx_hash.0);
+                                }
+                            }
+                            Err(e) => kiprintln!(
+                                "Failed to replace transaction 0x{} of epoch {}: {:?}",
+                                hex::encode(pending.tx_hash),
+                                pending.epoch,
+                                e
+                            ),
+                        }
+                    }
+                    Some(_) => {}
+                    // Sent before its block was known, start the deadline now
+                    None => self.nonces.set_sent_at_block(nonce, head),
+                },
+            }
+        }
+        self.save()
+    }
+
+    pub fn set_cycles(&mut self, epoch: u64, cycles: u64) {
+        if let Some(state) = self.epoch_history.get_mut(&epoch) {
+            state.cycles = Some(cycles);
+            self.save().unwrap_or_default();
+        }
+    }
+
+    // The aggregation of `epoch` failed and no aggregate of it will be used: its proofs go
+    // back into the current epoch, or the next ones where their node already submitted
+    pub fn fail_aggregation(&mut self, epoch: u64, reason: String) {
+        self.finish_aggregation(epoch);
+        if let Some(state) = self.epoch_history.get_mut(&epoch) {
+            state.aggregation_error = Some(reason);
+        }
+        self.requeue_epoch(epoch);
+        self.save().unwrap_or_default();
+    }
+
+    // Most recent epoch that has an aggregated proof
+    pub fn get_aggregated_proof(&self) -> Option<(u64, &SP1ProofWithPublicValues)> {
+        self.epoch_history.iter().rev().find_map(|(epoch, state)| {
+            state
+                .current_aggregated_proof
+                .as_ref()
+                .map(|proof| (*epoch, proof))
+        })
     }
 
     pub fn get_epoch_state(&self, epoch: u64) -> Option<&EpochState> {